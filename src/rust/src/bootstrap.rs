@@ -0,0 +1,66 @@
+use extendr_api::prelude::*;
+use ordered_float::OrderedFloat;
+use rayon::prelude::*;
+
+use crate::covariates::SortedCovariates;
+use crate::monitor::SearchMonitor;
+use crate::node::Node;
+use crate::options::SearchOptions;
+use crate::{SearchContext, TreeSearcher};
+
+impl SortedCovariates {
+    // Best tree of the given depth for each bootstrap (or subsample) replicate, searched in
+    // parallel. Column `b` of `counts` gives the number of times each row is drawn in replicate
+    // `b`: a row counts that many times over, on top of its sample weight and towards the node
+    // sizes, just as if it were repeated, and rows that are not drawn are left out of the search.
+    // The replicates share the sorted sets, and only differ in their rewards, weights and active
+    // rows. The search reads its depth, node sizes and bound pruning from `options`.
+    pub fn bootstrap_trees(
+        &self,
+        gamma: ArrayView2<f64>,
+        weights: ArrayView1<f64>,
+        counts: ArrayView2<f64>,
+        options: &SearchOptions,
+        monitor: &SearchMonitor,
+    ) -> Vec<Node> {
+        (0..counts.ncols())
+            .into_par_iter()
+            .map(|replicate| {
+                let counts = counts.column(replicate);
+                let weights = &weights * &counts;
+                let scores = gamma.map(|x| OrderedFloat(*x))
+                    * &weights.map(|w| OrderedFloat(*w)).insert_axis(Axis(1));
+                let sizes = if options.weighted_size {
+                    weights.clone()
+                } else {
+                    counts.to_owned()
+                };
+
+                let ctx = SearchContext::new(
+                    &self.sets,
+                    scores.view(),
+                    weights.view(),
+                    sizes.view(),
+                    options.min_node_size,
+                    options.bound_pruning,
+                    monitor,
+                )
+                .with_missing(&self.missing)
+                .with_categorical(&self.categorical)
+                .with_split_step(self.split_step);
+
+                let mut searcher = TreeSearcher::new_full(&ctx);
+                for (index, count) in counts.iter().enumerate() {
+                    if *count == 0.0 {
+                        searcher.remove(index);
+                    }
+                }
+
+                searcher
+                    .recursive_tree_search(options.depth, true, OrderedFloat(-f64::INFINITY))
+                    .or_else(|| searcher.best_leaf())
+                    .expect("every action is allowed without an eligibility mask")
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,67 @@
+use ordered_float::OrderedFloat;
+
+use crate::observation_bundle::ObservationBundle;
+
+// Most levels a categorical covariate may have. Every subset of its levels is tried as the left
+// side of a split, which takes 2^(levels - 1) - 1 steps per sweep.
+pub const MAX_CATEGORICAL_LEVELS: usize = 12;
+
+// SplitStep Struct. One step of a sweep along an axis: the observations of bundle `bundle` move to
+// the left side of the cut, or back to the right. Every state the sweep passes through after a
+// step is a candidate split.
+#[derive(Debug, Clone, Copy)]
+pub struct SplitStep {
+    pub bundle: usize,
+    pub to_left: bool,
+}
+
+// Steps along a numeric axis: the bundles move left one at a time in increasing order of their
+// values, so the candidate splits are the thresholds `x <= cut_point`.
+pub fn numeric_steps(n_bundles: usize) -> Vec<SplitStep> {
+    (0..n_bundles)
+        .map(|bundle| SplitStep {
+            bundle: bundle,
+            to_left: true,
+        })
+        .collect()
+}
+
+// Steps along a categorical axis, with one bundle per level. They follow a Gray code over all the
+// levels but the last, so every step moves a single level and the sweep visits each subset of
+// levels as the left side exactly once. The last level always stays on the right, since sending a
+// subset left or right gives the same split.
+pub fn categorical_steps(n_levels: usize) -> Vec<SplitStep> {
+    if n_levels < 2 {
+        return Vec::new();
+    }
+
+    (1..(1usize << (n_levels - 1)))
+        .map(|k| {
+            let bundle = k.trailing_zeros() as usize;
+            SplitStep {
+                bundle: bundle,
+                to_left: gray_code(k) & (1 << bundle) != 0,
+            }
+        })
+        .collect()
+}
+
+// Levels on the left side of the cut after step `step` of `categorical_steps`
+pub fn left_levels(levels: &[ObservationBundle], step: usize) -> Vec<OrderedFloat<f64>> {
+    let code = left_set(step);
+    levels
+        .iter()
+        .enumerate()
+        .filter(|(level, _)| code & (1 << level) != 0)
+        .map(|(_, bundle)| bundle.cut_point)
+        .collect()
+}
+
+// Bit mask of the levels on the left side of the cut after step `step` of `categorical_steps`
+pub fn left_set(step: usize) -> usize {
+    gray_code(step + 1)
+}
+
+fn gray_code(k: usize) -> usize {
+    k ^ (k >> 1)
+}
@@ -0,0 +1,109 @@
+use extendr_api::prelude::*;
+use ordered_float::OrderedFloat;
+
+use crate::node::{Node, NodeType};
+use crate::TreeSearcher;
+
+// Relative slack allowed when comparing the penalties at which branches are pruned, so that
+// branches that are pruned at the same penalty up to rounding error are pruned together
+const PATH_TOLERANCE: f64 = 1e-9;
+
+// The leaf each branch of a tree would be pruned to, laid out like the tree itself: the best leaf
+// over the observations reaching the branch, or None if the eligibility mask allows no action there
+struct Collapse {
+    leaf: Option<Node>,
+    children: Option<Box<(Collapse, Collapse)>>,
+}
+
+// Cost-complexity pruning, as in CART. Pruning a branch to a leaf loses the difference between the
+// reward of its subtree and that of the leaf, but saves all of its leaves but one, so it pays off
+// once the penalty per leaf exceeds that loss divided by the leaves saved. Pruning the branch with
+// the smallest such penalty over and over again gives a sequence of ever smaller subtrees, each one
+// the best subtree of the fitted tree for every penalty from the one it starts at up to the next.
+impl<'a> TreeSearcher<'a> {
+    // Pruning path of `tree` over the active observations, whose covariates are the rows of `x`:
+    // pairs of a penalty and the subtree that is best from that penalty on, in order of increasing
+    // penalty. The first subtree, at penalty 0, is `tree` with every branch that gains nothing over
+    // a leaf pruned; the path stops at a single leaf, or once no branch may be pruned. The rewards
+    // in `tree` must be plain total rewards, without any penalty.
+    pub fn pruning_path(&self, tree: &Node, x: ArrayView2<OrderedFloat<f64>>) -> Vec<(f64, Node)> {
+        let collapse = self.collapse_leaves(tree, x);
+
+        let mut tree = tree.clone();
+        prune_branches(&mut tree, &collapse, 0.0);
+        let mut path = vec![(0.0, tree.clone())];
+
+        while let Some(penalty) = weakest_link(&tree, &collapse) {
+            prune_branches(&mut tree, &collapse, penalty);
+            path.push((penalty, tree.clone()));
+        }
+        path
+    }
+
+    fn collapse_leaves(&self, tree: &Node, x: ArrayView2<OrderedFloat<f64>>) -> Collapse {
+        if tree.node_type == NodeType::Leaf {
+            return Collapse {
+                leaf: None,
+                children: None,
+            };
+        }
+
+        let leaf = self
+            .ctx
+            .best_action(&self.max_treatment_utils, &self.ineligible, self.n_active)
+            .map(|idx| Node::new_leaf(self.max_treatment_utils[idx], idx));
+
+        let (sets_l, sets_r) = self.route(tree, x);
+        let collapse_l = sets_l.collapse_leaves(tree.left_child.as_ref().unwrap(), x);
+        let collapse_r = sets_r.collapse_leaves(tree.right_child.as_ref().unwrap(), x);
+
+        Collapse {
+            leaf: leaf,
+            children: Some(Box::new((collapse_l, collapse_r))),
+        }
+    }
+}
+
+// Penalty per leaf above which pruning `tree` to `leaf` pays off
+fn pruning_penalty(tree: &Node, leaf: &Node) -> f64 {
+    (tree.reward - leaf.reward).0 / (tree.n_leaves() - 1) as f64
+}
+
+// Smallest penalty at which a branch of `tree` is pruned, or None if no branch may be pruned
+fn weakest_link(tree: &Node, collapse: &Collapse) -> Option<f64> {
+    if tree.node_type == NodeType::Leaf {
+        return None;
+    }
+
+    let (collapse_l, collapse_r) = collapse.children.as_deref().unwrap();
+    let own = collapse
+        .leaf
+        .as_ref()
+        .map(|leaf| pruning_penalty(tree, leaf));
+    let left = weakest_link(tree.left_child.as_ref().unwrap(), collapse_l);
+    let right = weakest_link(tree.right_child.as_ref().unwrap(), collapse_r);
+
+    [own, left, right].into_iter().flatten().reduce(f64::min)
+}
+
+// Prunes every branch of `tree` that pays off at `penalty`. Pruning a branch never makes pruning
+// the branches above it pay off at a smaller penalty, so a single pass from the top is enough.
+fn prune_branches(tree: &mut Node, collapse: &Collapse, penalty: f64) {
+    if tree.node_type == NodeType::Leaf {
+        return;
+    }
+
+    if let Some(leaf) = &collapse.leaf {
+        if pruning_penalty(tree, leaf) <= penalty + PATH_TOLERANCE * (1.0 + penalty.abs()) {
+            *tree = leaf.clone();
+            return;
+        }
+    }
+
+    let (collapse_l, collapse_r) = collapse.children.as_deref().unwrap();
+    let left_child = tree.left_child.as_mut().unwrap();
+    let right_child = tree.right_child.as_mut().unwrap();
+    prune_branches(left_child, collapse_l, penalty);
+    prune_branches(right_child, collapse_r, penalty);
+    tree.reward = left_child.reward + right_child.reward;
+}
@@ -0,0 +1,275 @@
+use rayon::prelude::*;
+use std::cmp::Reverse;
+
+use crate::node::Node;
+use crate::TreeSearcher;
+
+// Relative slack allowed when checking the capacities, which are compared against sums of sample
+// weights that are updated incrementally as observations move between searchers
+const CAPACITY_TOLERANCE: f64 = 1e-9;
+
+// Capacity Struct. Per-action limits on the population a tree may assign: at most `limits[i]` may
+// receive action `actions[i]`, in units of sample weight, or in units when `counts` is set.
+// Actions without a limit are left out, so an empty `Capacity` means the search is unconstrained.
+pub struct Capacity {
+    actions: Vec<usize>,
+    limits: Vec<f64>,
+    counts: bool,
+}
+
+impl Capacity {
+    // `limits` holds the largest share of the (weighted) population each action may be assigned,
+    // or with `counts` the largest number of units, whatever their weights. Limits that all of
+    // the `n_units` units, of total weight `population`, fit within, such as infinite ones, are
+    // left out.
+    pub fn new(limits: &[f64], counts: bool, population: f64, n_units: usize) -> Self {
+        let total = if counts { n_units as f64 } else { population };
+
+        let mut actions = Vec::new();
+        let mut absolute = Vec::new();
+        for (action, limit) in limits.iter().enumerate() {
+            let limit = if counts { *limit } else { limit * total };
+            if limit < total {
+                actions.push(action);
+                absolute.push(limit);
+            }
+        }
+
+        Capacity {
+            actions: actions,
+            limits: absolute,
+            counts: counts,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.actions.is_empty()
+    }
+
+    fn allows(&self, usage: &[f64]) -> bool {
+        usage
+            .iter()
+            .zip(self.limits.iter())
+            .all(|(used, limit)| *used <= limit + CAPACITY_TOLERANCE * (1.0 + limit.abs()))
+    }
+}
+
+// A tree along with the population it assigns to each of the limited actions, in the order of
+// `Capacity::actions`
+#[derive(Clone)]
+struct Candidate {
+    tree: Node,
+    usage: Vec<f64>,
+}
+
+impl Candidate {
+    // Whether `self` is at least as good as `other` in reward while using no more of any capacity,
+    // in which case `other` can never be part of a better feasible tree than `self`
+    fn dominates(&self, other: &Candidate) -> bool {
+        self.tree.reward >= other.tree.reward
+            && self
+                .usage
+                .iter()
+                .zip(other.usage.iter())
+                .all(|(mine, theirs)| mine <= theirs)
+    }
+}
+
+// Reduces a list of candidates over the same observations to its Pareto frontier, sorted from the
+// highest reward down. The sort is stable and earlier candidates are kept over later ones they
+// dominate, so ties go the same way as in the unconstrained search (see `first_best`).
+fn pareto_frontier(mut candidates: Vec<Candidate>) -> Vec<Candidate> {
+    candidates.sort_by_key(|candidate| Reverse(candidate.tree.reward));
+
+    let mut frontier: Vec<Candidate> = Vec::new();
+    for candidate in candidates {
+        if !frontier.iter().any(|kept| kept.dominates(&candidate)) {
+            frontier.push(candidate);
+        }
+    }
+    frontier
+}
+
+// Constrained tree search. A capacity constraint couples the leaves of a tree, so the best subtree
+// for each side of a split is no longer enough: a worse subtree may leave more room for the other
+// side. Instead every search returns the Pareto frontier of feasible subtrees, trading reward off
+// against the capacity they use, and a split combines every pair from its two sides' frontiers.
+// The optimal feasible tree is the best candidate of the frontier at the root. This makes the
+// search exact, but much more expensive than the unconstrained one, and bound pruning isn't used.
+impl<'a> TreeSearcher<'a> {
+    // Best tree of the given depth that respects `capacity`, or None if there is no such tree
+    pub fn best_constrained_tree(&self, depth: usize, capacity: &Capacity) -> Option<Node> {
+        self.frontier_tree_search(depth, true, capacity)
+            .into_iter()
+            .next()
+            .map(|candidate| candidate.tree)
+    }
+
+    // Leaves giving every active unit one action, for each action that the capacity and the
+    // eligibility mask allow
+    fn leaf_frontier(&self, capacity: &Capacity) -> Vec<Candidate> {
+        let nd: usize = self.ctx.scores.dim().1;
+
+        let candidates = (0..nd)
+            .filter(|action| self.ctx.allowed(self.ineligible[*action], self.n_active))
+            .map(|action| Candidate {
+                tree: self.ctx.leaf(self.max_treatment_utils[action], action),
+                usage: capacity
+                    .actions
+                    .iter()
+                    .map(|limited| {
+                        if *limited != action {
+                            0.0
+                        } else if capacity.counts {
+                            self.n_active as f64
+                        } else {
+                            self.population
+                        }
+                    })
+                    .collect(),
+            })
+            .filter(|candidate| capacity.allows(&candidate.usage))
+            .collect();
+
+        pareto_frontier(candidates)
+    }
+
+    // Counterpart of `recursive_tree_search`, returning the frontier of feasible trees of the given
+    // depth over the active observations. Falls back to leaves when the node is too small to split.
+    fn frontier_tree_search(&self, depth: usize, top: bool, capacity: &Capacity) -> Vec<Candidate> {
+        if depth == 0 {
+            return self.leaf_frontier(capacity);
+        }
+
+        let np: usize = self.ctx.sets.len();
+        let splits: Vec<Option<Vec<Candidate>>> = if top {
+            (0..np)
+                .into_par_iter()
+                .map(|dim| self.single_dimension_frontier_search(dim, depth, top, capacity))
+                .collect()
+        } else {
+            (0..np)
+                .map(|dim| self.single_dimension_frontier_search(dim, depth, top, capacity))
+                .collect()
+        };
+
+        let mut found_split = false;
+        let mut candidates = Vec::new();
+        for frontier in splits.into_iter().flatten() {
+            found_split = true;
+            candidates.extend(frontier);
+        }
+
+        // Leaves compete with the splits under an eligibility mask or a penalty, see `leaves_compete`
+        if !found_split || self.ctx.leaves_compete() {
+            candidates.extend(self.leaf_frontier(capacity));
+        }
+
+        pareto_frontier(candidates)
+    }
+
+    // Frontier of the feasible trees splitting on `dim` at the top, with the rows missing it on
+    // either side, or None if no cut point along it is allowed by `min_node_size`
+    fn single_dimension_frontier_search(
+        &self,
+        dim: usize,
+        depth: usize,
+        top: bool,
+        capacity: &Capacity,
+    ) -> Option<Vec<Candidate>> {
+        let n_cuts = self.ctx.steps[dim].len();
+
+        let mut candidates: Vec<Candidate> = Vec::new();
+        let mut frontier_size: usize = 0;
+        let mut found_split = false;
+
+        for &missing_left in self.ctx.missing_directions(dim) {
+            let mut sets_r = self.clone();
+            let mut sets_l = Self::new_empty(self.ctx);
+
+            if missing_left {
+                for index in self.ctx.missing(dim) {
+                    if self.active[*index] {
+                        sets_l.add(*index);
+                        sets_r.remove(*index);
+                    }
+                }
+            }
+            let n_missing_left = sets_l.n_active;
+
+            for (i, step) in self.ctx.steps[dim].iter().enumerate() {
+                if self.ctx.monitor.should_stop() {
+                    break;
+                }
+
+                if top {
+                    self.ctx.monitor.top_cuts_finished(1);
+                }
+                self.ctx.monitor.cuts_processed(1);
+
+                self.move_bundle(dim, *step, &mut sets_l, &mut sets_r);
+
+                if sets_r.size < self.ctx.min_node_size {
+                    if !self.ctx.monotone(dim) {
+                        continue;
+                    }
+                    if top {
+                        self.ctx.monitor.top_cuts_finished(n_cuts - i - 1);
+                    }
+                    break;
+                }
+                if sets_l.size < self.ctx.min_node_size {
+                    continue;
+                }
+                if !self
+                    .ctx
+                    .on_split_step(dim, sets_l.n_active - n_missing_left)
+                {
+                    continue;
+                }
+                found_split = true;
+
+                let frontier_l = sets_l.frontier_tree_search(depth - 1, false, capacity);
+                let frontier_r = sets_r.frontier_tree_search(depth - 1, false, capacity);
+
+                for tree_l in frontier_l.iter() {
+                    for tree_r in frontier_r.iter() {
+                        let usage: Vec<f64> = tree_l
+                            .usage
+                            .iter()
+                            .zip(tree_r.usage.iter())
+                            .map(|(left, right)| left + right)
+                            .collect();
+                        if !capacity.allows(&usage) {
+                            continue;
+                        }
+
+                        candidates.push(Candidate {
+                            tree: self
+                                .ctx
+                                .branch(tree_l.tree.clone(), tree_r.tree.clone(), dim, i)
+                                .with_missing_left(missing_left),
+                            usage: usage,
+                        });
+                    }
+                }
+
+                // Thin the candidates out every so often, so that they don't pile up over the cut points
+                if candidates.len() > 2 * frontier_size + 64 {
+                    candidates = pareto_frontier(candidates);
+                    frontier_size = candidates.len();
+                }
+            }
+        }
+
+        if top {
+            self.ctx.monitor.dimension_finished();
+        }
+
+        if !found_split {
+            return None;
+        }
+
+        Some(pareto_frontier(candidates))
+    }
+}
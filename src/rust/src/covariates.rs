@@ -0,0 +1,54 @@
+use extendr_api::prelude::*;
+use ordered_float::OrderedFloat;
+
+use crate::observation_bundle::ObservationBundle;
+use crate::{check_categorical_levels, missing_rows, new_sorted_sets};
+
+// SortedCovariates Struct. Everything the search needs from the covariates: the matrix itself, for
+// routing observations through fitted trees, its sorted sets and the rows missing each covariate,
+// along with the `split_step` to search them with. None of it depends on the rewards or on which rows are searched, so it is built once, handed to
+// R behind an external pointer, and reused by every search over the same covariates.
+#[derive(Debug)]
+pub struct SortedCovariates {
+    pub x: Array2<OrderedFloat<f64>>,
+    pub sets: Vec<Vec<ObservationBundle>>,
+    pub missing: Vec<Vec<usize>>,
+    pub categorical: Vec<bool>,
+    pub split_step: usize,
+}
+
+impl SortedCovariates {
+    // Sorts the columns of `x` as `new_sorted_sets` does, failing if a categorical covariate has
+    // too many levels
+    pub fn new(x: ArrayView2<f64>, split_step: usize, categorical: Vec<bool>) -> Result<Self> {
+        let x = x.map(|value| OrderedFloat(*value));
+        let sets = new_sorted_sets(x.view());
+        check_categorical_levels(&sets, &categorical)?;
+        let missing = missing_rows(x.view());
+
+        Ok(SortedCovariates {
+            x: x,
+            sets: sets,
+            missing: missing,
+            categorical: categorical,
+            split_step: split_step,
+        })
+    }
+
+    // The covariates behind `robj`, an external pointer made by `rust_sorted_covariates`. R saves
+    // external pointers without their address, so a pointer restored from a saved workspace comes
+    // back null; that is reported as an error instead of being dereferenced.
+    pub fn from_pointer(robj: &Robj) -> Result<ExternalPtr<SortedCovariates>> {
+        let pointer = ExternalPtr::<SortedCovariates>::try_from(robj).map_err(|_| {
+            Error::Other("Expected covariates sorted by `sorted_covariates`".to_string())
+        })?;
+        if unsafe { robj.external_ptr_addr::<SortedCovariates>() }.is_null() {
+            return Err(Error::Other(
+                "The sorted covariates did not survive saving and reloading the R session; \
+                 rebuild the sorted covariates with `sorted_covariates`"
+                    .to_string(),
+            ));
+        }
+        Ok(pointer)
+    }
+}
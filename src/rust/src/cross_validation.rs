@@ -0,0 +1,58 @@
+use extendr_api::prelude::*;
+use ordered_float::OrderedFloat;
+
+use crate::{SearchContext, TreeSearcher};
+
+// Cross-validation over the depth and the leaf penalty. Every fold is held out in turn, and trees
+// are searched over the other folds by taking the held-out rows out of the active mask, so the
+// sorted sets are built once for all folds and configurations. The penalty is charged in the units
+// of the total reward, which shrinks with the training set, so each fold charges it in proportion
+// to its share of the (weighted) population; the trees then match what a fit on all of the data
+// with the same penalty would be aiming for. Trees are scored on the rows they were not searched on.
+impl<'a> SearchContext<'a> {
+    // Out-of-fold reward of the best tree for every combination of `depths` and `penalties`, given
+    // the fold of each row in `folds` (0-based) and their covariates in `x`: for each depth, then
+    // each penalty within it, the summed (weighted) reward on each of the `n_folds` folds
+    pub fn cross_validate(
+        &self,
+        x: ArrayView2<OrderedFloat<f64>>,
+        folds: &[usize],
+        n_folds: usize,
+        depths: &[usize],
+        penalties: &[f64],
+    ) -> Vec<Vec<f64>> {
+        let total_population: f64 = self.weights.sum();
+        let mut rewards = vec![vec![0.0; n_folds]; depths.len() * penalties.len()];
+
+        for fold in 0..n_folds {
+            let held_out: Vec<usize> = (0..folds.len()).filter(|row| folds[*row] == fold).collect();
+            let held_out_population: f64 = held_out.iter().map(|row| self.weights[*row]).sum();
+            let share = 1.0 - held_out_population / total_population;
+
+            for (p, penalty) in penalties.iter().enumerate() {
+                let ctx = self.penalised(penalty * share);
+                let mut searcher = TreeSearcher::new_full(&ctx);
+                for row in held_out.iter() {
+                    searcher.remove(*row);
+                }
+
+                for (d, depth) in depths.iter().enumerate() {
+                    if self.monitor.should_stop() {
+                        return rewards;
+                    }
+
+                    let tree = searcher
+                        .recursive_tree_search(*depth, true, OrderedFloat(-f64::INFINITY))
+                        .or_else(|| searcher.best_leaf())
+                        .expect("every action is allowed without an eligibility mask");
+                    rewards[d * penalties.len() + p][fold] = held_out
+                        .iter()
+                        .map(|row| self.scores[[*row, tree.action_for(x.row(*row))]].0)
+                        .sum();
+                }
+            }
+        }
+
+        rewards
+    }
+}
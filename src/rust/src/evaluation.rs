@@ -0,0 +1,109 @@
+use extendr_api::prelude::*;
+use rayon::prelude::*;
+
+use crate::predict::FlatTree;
+
+// Running mean and sum of squared deviations of a sample, updated one value at a time and merged
+// across threads with the pairwise formulas of Chan et al., which stay accurate where a running
+// sum of squares would cancel out
+#[derive(Debug, Clone, Copy)]
+struct Moments {
+    n: f64,
+    mean: f64,
+    m2: f64,
+}
+
+impl Moments {
+    fn new() -> Self {
+        Moments {
+            n: 0.0,
+            mean: 0.0,
+            m2: 0.0,
+        }
+    }
+
+    fn push(&mut self, value: f64) {
+        self.n += 1.0;
+        let delta = value - self.mean;
+        self.mean += delta / self.n;
+        self.m2 += delta * (value - self.mean);
+    }
+
+    fn merge(self, other: Moments) -> Moments {
+        if self.n == 0.0 {
+            return other;
+        }
+        if other.n == 0.0 {
+            return self;
+        }
+        let n = self.n + other.n;
+        let delta = other.mean - self.mean;
+        Moments {
+            n: n,
+            mean: self.mean + delta * other.n / n,
+            m2: self.m2 + other.m2 + delta * delta * self.n * other.n / n,
+        }
+    }
+
+    // Standard error of the mean, NaN with fewer than two values
+    fn std_err(&self) -> f64 {
+        (self.m2 / (self.n - 1.0) / self.n).sqrt()
+    }
+}
+
+// Estimate of a mean along with its standard error
+pub struct Estimate {
+    pub estimate: f64,
+    pub std_err: f64,
+}
+
+impl From<Moments> for Estimate {
+    fn from(moments: Moments) -> Self {
+        Estimate {
+            estimate: moments.mean,
+            std_err: moments.std_err(),
+        }
+    }
+}
+
+impl<'a> FlatTree<'a> {
+    // Value of the tree's policy on held-out data, with covariates `x` and rewards `gamma` (such as
+    // doubly robust scores): the mean reward of the recommended actions, and for every action the
+    // mean difference between that and the reward of giving everyone the action. Each unit counts
+    // once, and the standard errors treat the units as independent. One parallel pass.
+    pub fn policy_value(
+        &self,
+        x: ArrayView2<f64>,
+        gamma: ArrayView2<f64>,
+    ) -> (Estimate, Vec<Estimate>) {
+        let nd = gamma.ncols();
+
+        let moments = (0..x.nrows())
+            .into_par_iter()
+            .fold(
+                || vec![Moments::new(); nd + 1],
+                |mut moments, row| {
+                    let reward = gamma[[row, self.action(x.row(row))]];
+                    moments[0].push(reward);
+                    for action in 0..nd {
+                        moments[action + 1].push(reward - gamma[[row, action]]);
+                    }
+                    moments
+                },
+            )
+            .reduce(
+                || vec![Moments::new(); nd + 1],
+                |first, second| {
+                    first
+                        .into_iter()
+                        .zip(second)
+                        .map(|(a, b)| a.merge(b))
+                        .collect()
+                },
+            );
+
+        let value = Estimate::from(moments[0]);
+        let differences = moments[1..].iter().map(|m| Estimate::from(*m)).collect();
+        (value, differences)
+    }
+}
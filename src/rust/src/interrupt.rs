@@ -0,0 +1,14 @@
+use libR_sys::{R_CheckUserInterrupt, R_ToplevelExec, Rboolean_FALSE};
+use std::os::raw::c_void;
+
+unsafe extern "C" fn check_interrupt_fn(_data: *mut c_void) {
+    R_CheckUserInterrupt();
+}
+
+// Returns true if the user has asked R to interrupt, e.g. by pressing Ctrl-C. On an interrupt,
+// R_CheckUserInterrupt longjmps straight out of the caller, which must never happen across Rust
+// frames, so it is run under R_ToplevelExec, which catches the jump and returns FALSE instead.
+// Must only be called from the main R thread.
+pub fn user_interrupted() -> bool {
+    unsafe { R_ToplevelExec(Some(check_interrupt_fn), std::ptr::null_mut()) == Rboolean_FALSE }
+}
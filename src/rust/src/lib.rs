@@ -1,8 +1,20 @@
 use extendr_api::prelude::*;
-use iter_utils::argmax;
 use ordered_float::OrderedFloat;
 use rayon::prelude::*;
 use std::collections::BTreeMap;
+use std::ops::Range;
+
+// Relative slack allowed when comparing incrementally updated reward bounds, see `may_improve`
+const BOUND_TOLERANCE: f64 = 1e-9;
+
+// Absolute slack allowed when comparing a leaf's number of eligible units to the required fraction
+const ELIGIBILITY_TOLERANCE: f64 = 1e-9;
+
+// Parallel search granularity. An axis is only split into chunks of cut points when the number of
+// cut points times the number of active observations reaches PARALLEL_MIN_WORK, and then into
+// CHUNKS_PER_THREAD chunks per thread so that rayon can balance uneven chunks.
+const PARALLEL_MIN_WORK: usize = 1 << 16;
+const CHUNKS_PER_THREAD: usize = 4;
 
 pub mod node;
 use crate::node::Node;
@@ -10,8 +22,47 @@ use crate::node::Node;
 pub mod observation_bundle;
 use crate::observation_bundle::ObservationBundle;
 
+pub mod monitor;
+use crate::monitor::{run_monitored, SearchMonitor};
+
+pub mod interrupt;
+use crate::interrupt::user_interrupted;
+
+pub mod constrained;
+use crate::constrained::Capacity;
+
+pub mod complexity;
+
+pub mod ranked;
+
+pub mod statistics;
+
+pub mod predict;
+use crate::predict::FlatTree;
+
+pub mod evaluation;
+
+pub mod scores;
+use crate::scores::aipw_scores;
+
+pub mod cross_validation;
+
+pub mod covariates;
+use crate::covariates::SortedCovariates;
+
+pub mod bootstrap;
+
+pub mod options;
+use crate::options::SearchOptions;
+
+pub mod categorical;
+use crate::categorical::{
+    categorical_steps, left_levels, numeric_steps, SplitStep, MAX_CATEGORICAL_LEVELS,
+};
+
 // Creates Sorted Sets from a view of the original datasets. Sorts sets using binary trees, then
-// turns them into a vector of observation bundles to save time
+// turns them into a vector of observation bundles to save time, one for each distinct value.
+// Missing values (NaN) are left out; see `missing_rows`.
 fn new_sorted_sets(dataset: ArrayView2<OrderedFloat<f64>>) -> Vec<Vec<ObservationBundle>> {
     // Create new vetor to store binary tree maps
     let mut btree_vec = Vec::new();
@@ -23,6 +74,9 @@ fn new_sorted_sets(dataset: ArrayView2<OrderedFloat<f64>>) -> Vec<Vec<Observatio
     // enter every row of the dataset into the sorted sets
     for (x, row) in dataset.axis_iter(Axis(0)).enumerate() {
         for (y, entry) in row.iter().enumerate() {
+            if entry.is_nan() {
+                continue;
+            }
             if !btree_vec[y].contains_key(entry) {
                 btree_vec[y].insert(*entry, ObservationBundle::new(*entry, x));
             } else {
@@ -34,52 +88,346 @@ fn new_sorted_sets(dataset: ArrayView2<OrderedFloat<f64>>) -> Vec<Vec<Observatio
 
     // move from binary trees to vectors of ObservationBundles
     let mut sorted_sets = Vec::new();
-    for btree in btree_vec {
-        let mut sorted_set = Vec::new();
-        for obs_bundle in btree.into_values() {
-            sorted_set.push(obs_bundle);
-        }
+    for btree in btree_vec.into_iter() {
+        let sorted_set: Vec<ObservationBundle> = btree.into_values().collect();
         sorted_sets.push(sorted_set);
     }
 
     return sorted_sets;
 }
 
+// Rows with a missing value (NaN) in each column of the dataset. These are kept out of the sorted
+// sets and sent as a block to whichever side of each cut does best, as in the "missing
+// incorporated in attributes" approach of grf and policytree.
+fn missing_rows(dataset: ArrayView2<OrderedFloat<f64>>) -> Vec<Vec<usize>> {
+    dataset
+        .axis_iter(Axis(1))
+        .map(|column| {
+            column
+                .iter()
+                .enumerate()
+                .filter(|(_, entry)| entry.is_nan())
+                .map(|(row, _)| row)
+                .collect()
+        })
+        .collect()
+}
+
+// Inputs shared by every searcher taking part in one tree search. None of this changes during the
+// search, so searchers only hold a reference to it and stay cheap to clone. `scores` are already
+// scaled by the sample weights; `sizes` is what each observation counts for towards
+// `min_node_size`, 1 for every unit or its weight when node sizes are weighted. `weights` are the
+// sample weights themselves, which measure the population assigned to each action. With an
+// eligibility mask, `ineligible` is 1 where a unit may not receive an action, and a leaf may only
+// recommend an action that at least `eligibility_fraction` of its units are eligible for.
+// `missing` lists the rows missing each covariate, if any covariate has missing values. `steps`
+// are the sweeps searched along each axis, see `categorical`, and `split_step` thins out the cut
+// points of the numeric ones, see `on_split_step`. Every leaf is charged
+// `leaf_penalty`, so the search maximises the total reward minus the penalty times the number of
+// leaves; see `complexity` for the matching pruning path.
+struct SearchContext<'a> {
+    sets: &'a Vec<Vec<ObservationBundle>>,
+    steps: Vec<Vec<SplitStep>>,
+    categorical: Vec<bool>,
+    split_step: usize,
+    missing: Option<&'a Vec<Vec<usize>>>,
+    scores: ArrayView2<'a, OrderedFloat<f64>>,
+    row_max: Array1<OrderedFloat<f64>>,
+    weights: ArrayView1<'a, f64>,
+    sizes: ArrayView1<'a, f64>,
+    min_node_size: f64,
+    ineligible: Option<ArrayView2<'a, usize>>,
+    eligibility_fraction: f64,
+    leaf_penalty: OrderedFloat<f64>,
+    bound_pruning: bool,
+    monitor: &'a SearchMonitor,
+}
+
+impl<'a> SearchContext<'a> {
+    fn new(
+        sets: &'a Vec<Vec<ObservationBundle>>,
+        scores: ArrayView2<'a, OrderedFloat<f64>>,
+        weights: ArrayView1<'a, f64>,
+        sizes: ArrayView1<'a, f64>,
+        min_node_size: f64,
+        bound_pruning: bool,
+        monitor: &'a SearchMonitor,
+    ) -> Self {
+        let row_max = scores.map_axis(Axis(1), |row| *row.iter().max().unwrap());
+
+        SearchContext {
+            sets: sets,
+            steps: sets.iter().map(|set| numeric_steps(set.len())).collect(),
+            categorical: vec![false; sets.len()],
+            split_step: 1,
+            missing: None,
+            scores: scores,
+            row_max: row_max,
+            weights: weights,
+            sizes: sizes,
+            min_node_size: min_node_size,
+            ineligible: None,
+            eligibility_fraction: 1.0,
+            leaf_penalty: OrderedFloat(0.0),
+            bound_pruning: bound_pruning,
+            monitor: monitor,
+        }
+    }
+
+    // Marks the covariates to split on by subsets of their levels rather than by thresholds
+    fn with_categorical(mut self, categorical: &[bool]) -> Self {
+        for (dim, is_categorical) in categorical.iter().enumerate() {
+            if *is_categorical {
+                self.steps[dim] = categorical_steps(self.sets[dim].len());
+            }
+        }
+        self.categorical = categorical.to_vec();
+        self
+    }
+
+    fn with_split_step(mut self, split_step: usize) -> Self {
+        self.split_step = split_step;
+        self
+    }
+
+    // Whether a sweep along `dim` may cut once `n_sorted` of the node's observations with a value
+    // have moved left. As in policytree, with `split_step` > 1 a numeric axis is only cut after
+    // every n'th of them in sorted order (the 1st, the (n + 1)th, ...), and not at all where that
+    // one shares its value with the next. The count starts afresh in every node.
+    fn on_split_step(&self, dim: usize, n_sorted: usize) -> bool {
+        !self.monotone(dim) || self.split_step == 1 || n_sorted % self.split_step == 1
+    }
+
+    // Whether observations only ever move left during a sweep along `dim`, so that once the right
+    // side of a cut is too small it stays too small for the rest of the sweep
+    fn monotone(&self, dim: usize) -> bool {
+        !self.categorical[dim]
+    }
+
+    // Branch splitting along `dim` as the sweep does after step `step`
+    fn branch(&self, tree_l: Node, tree_r: Node, dim: usize, step: usize) -> Node {
+        if self.categorical[dim] {
+            Node::new_categorical_branch(tree_l, tree_r, dim, left_levels(&self.sets[dim], step))
+        } else {
+            let cut_point = self.sets[dim][self.steps[dim][step].bundle].cut_point;
+            Node::new_branch(tree_l, tree_r, dim, cut_point)
+        }
+    }
+
+    fn with_missing(mut self, missing: &'a Vec<Vec<usize>>) -> Self {
+        if missing.iter().any(|rows| !rows.is_empty()) {
+            self.missing = Some(missing);
+        }
+        self
+    }
+
+    // Rows missing covariate `dim`
+    fn missing(&self, dim: usize) -> &[usize] {
+        match self.missing {
+            Some(missing) => &missing[dim],
+            None => &[],
+        }
+    }
+
+    // Sides the rows missing covariate `dim` may be sent to at a cut on it, as values of
+    // `send_missing_left`. Every axis with missing values is searched once for each.
+    fn missing_directions(&self, dim: usize) -> &'static [bool] {
+        if self.missing(dim).is_empty() {
+            &[false]
+        } else {
+            &[false, true]
+        }
+    }
+
+    fn with_eligibility(mut self, ineligible: ArrayView2<'a, usize>, fraction: f64) -> Self {
+        self.ineligible = Some(ineligible);
+        self.eligibility_fraction = fraction;
+        self
+    }
+
+    // Whether a leaf over `n_units` observations, `n_ineligible` of which may not receive an action,
+    // may recommend that action
+    fn allowed(&self, n_ineligible: usize, n_units: usize) -> bool {
+        if self.ineligible.is_none() {
+            return true;
+        }
+        let n_eligible = (n_units - n_ineligible) as f64;
+        n_eligible + ELIGIBILITY_TOLERANCE >= self.eligibility_fraction * n_units as f64
+    }
+
+    fn with_leaf_penalty(mut self, penalty: f64) -> Self {
+        self.leaf_penalty = OrderedFloat(penalty);
+        self
+    }
+
+    // Context over the same inputs that charges `penalty` to every leaf instead. It borrows them
+    // afresh, so it only has to live as long as the searchers built on it.
+    fn penalised(&self, penalty: f64) -> SearchContext<'_> {
+        SearchContext {
+            sets: self.sets,
+            steps: self.steps.clone(),
+            categorical: self.categorical.clone(),
+            split_step: self.split_step,
+            missing: self.missing,
+            scores: self.scores.view(),
+            row_max: self.row_max.clone(),
+            weights: self.weights.view(),
+            sizes: self.sizes.view(),
+            min_node_size: self.min_node_size,
+            ineligible: self.ineligible.as_ref().map(|ineligible| ineligible.view()),
+            eligibility_fraction: self.eligibility_fraction,
+            leaf_penalty: OrderedFloat(penalty),
+            bound_pruning: self.bound_pruning,
+            monitor: self.monitor,
+        }
+    }
+
+    // Leaf recommending `action`, with `reward` from giving it to all of its units, less the penalty
+    fn leaf(&self, reward: OrderedFloat<f64>, action: usize) -> Node {
+        Node::new_leaf(reward - self.leaf_penalty, action)
+    }
+
+    // Whether a leaf may do better than every split of a node, in which case it has to be compared
+    // with them: when only a fraction of its units has to be eligible for its action, they may
+    // reach that fraction together but not on either side, and a penalty makes a leaf cheaper than
+    // any split.
+    fn leaves_compete(&self) -> bool {
+        self.ineligible.is_some() || self.leaf_penalty > OrderedFloat(0.0)
+    }
+
+    // Action a leaf over `n_units` observations recommends, given the rewards from giving all of
+    // them each action and how many of them are ineligible for each: the one with the largest
+    // reward among those allowed, taking the lowest index on ties, or None if none is allowed
+    fn best_action(
+        &self,
+        rewards: &Array1<OrderedFloat<f64>>,
+        ineligible: &Array1<usize>,
+        n_units: usize,
+    ) -> Option<usize> {
+        let mut best: Option<usize> = None;
+        for (action, reward) in rewards.iter().enumerate() {
+            if !self.allowed(ineligible[action], n_units) {
+                continue;
+            }
+            match best {
+                Some(best_action) if *reward <= rewards[best_action] => {}
+                _ => best = Some(action),
+            }
+        }
+        best
+    }
+}
+
+// Running totals for the observations on one side of a cut, as kept by `search_single_split` for
+// the two leaves of a split: their rewards from each action, how many of them are ineligible for
+// each action, their number and their size.
+struct SideTotals {
+    rewards: Array1<OrderedFloat<f64>>,
+    ineligible: Array1<usize>,
+    n_units: usize,
+    size: f64,
+}
+
+impl SideTotals {
+    fn new_empty(nd: usize) -> Self {
+        SideTotals {
+            rewards: Array1::from_elem(nd, OrderedFloat(0.0)),
+            ineligible: Array1::zeros(nd),
+            n_units: 0,
+            size: 0.0,
+        }
+    }
+
+    fn new_full(searcher: &TreeSearcher) -> Self {
+        SideTotals {
+            rewards: searcher.max_treatment_utils.clone(),
+            ineligible: searcher.ineligible.clone(),
+            n_units: searcher.n_active,
+            size: searcher.size,
+        }
+    }
+
+    fn add(&mut self, ctx: &SearchContext, index: usize) {
+        self.rewards += &ctx.scores.index_axis(Axis(0), index);
+        if let Some(ineligible) = &ctx.ineligible {
+            self.ineligible += &ineligible.index_axis(Axis(0), index);
+        }
+        self.n_units += 1;
+        self.size += ctx.sizes[index];
+    }
+
+    fn remove(&mut self, ctx: &SearchContext, index: usize) {
+        self.rewards -= &ctx.scores.index_axis(Axis(0), index);
+        if let Some(ineligible) = &ctx.ineligible {
+            self.ineligible -= &ineligible.index_axis(Axis(0), index);
+        }
+        self.n_units -= 1;
+        self.size -= ctx.sizes[index];
+    }
+
+    // Best leaf over these observations, or None if the eligibility mask allows no action
+    fn best_leaf(&self, ctx: &SearchContext) -> Option<Node> {
+        let idx = ctx.best_action(&self.rewards, &self.ineligible, self.n_units)?;
+        Some(ctx.leaf(self.rewards[idx], idx))
+    }
+}
+
+// Ties between equally good trees are always broken the same way, so the same data gives the same
+// tree whatever the number of threads: at each node the split on the lowest axis wins, then the one
+// with the lowest cut point (on a categorical axis, the one reached first by its sweep), and a leaf
+// recommends the lowest-numbered of its best actions. Serial loops get this by only replacing the
+// best split on a strict improvement (and `best_action` keeping the first maximum); parallel
+// results are combined in order by `first_best`.
+
 // Tree Search Struct. Keeps a reference to the sorted sets, but does not change them so they don't
 // have to be copied / modified. The observations that are in consideration are stored in the
 // `active` field, which is a boolean vector Also keeps track of the utility from giving every unit
 // each of the possible treatments, which cuts out the use of an array in the `search single
-// dimension` part of the algotithm
+// dimension` part of the algotithm. `n_active` counts the active observations, and `size` adds up
+// their sizes so that cuts leaving less than `min_node_size` on either side can be skipped without
+// a pass over `active`. `population` adds up their sample weights, for the capacity constraints,
+// and `ineligible` counts the active units that are ineligible for each action.
+// `upper_bound` is the reward from giving every active unit its own best action, which no tree
+// over these units can beat, and drives the branch-and-bound pruning.
 #[derive(Clone)]
 struct TreeSearcher<'a> {
-    sets: &'a Vec<Vec<ObservationBundle>>,
+    ctx: &'a SearchContext<'a>,
     active: Array1<bool>,
-    scores: ArrayView2<'a, OrderedFloat<f64>>,
+    n_active: usize,
+    size: f64,
+    population: f64,
     max_treatment_utils: Array1<OrderedFloat<f64>>,
+    ineligible: Array1<usize>,
+    upper_bound: OrderedFloat<f64>,
 }
 
 impl<'a> TreeSearcher<'a> {
-    fn new_empty(
-        sets: &'a Vec<Vec<ObservationBundle>>,
-        scores: ArrayView2<'a, OrderedFloat<f64>>,
-    ) -> Self {
+    fn new_empty(ctx: &'a SearchContext<'a>) -> Self {
         TreeSearcher {
-            sets: sets,
-            scores: scores,
-            active: Array1::from_elem(scores.dim().0, false),
-            max_treatment_utils: Array1::from_elem(scores.dim().1, OrderedFloat(0.0)),
+            ctx: ctx,
+            active: Array1::from_elem(ctx.scores.dim().0, false),
+            n_active: 0,
+            size: 0.0,
+            population: 0.0,
+            max_treatment_utils: Array1::from_elem(ctx.scores.dim().1, OrderedFloat(0.0)),
+            ineligible: Array1::zeros(ctx.scores.dim().1),
+            upper_bound: OrderedFloat(0.0),
         }
     }
 
-    fn new_full(
-        sets: &'a Vec<Vec<ObservationBundle>>,
-        scores: ArrayView2<'a, OrderedFloat<f64>>,
-    ) -> Self {
+    fn new_full(ctx: &'a SearchContext<'a>) -> Self {
         let out = TreeSearcher {
-            sets: sets,
-            scores: scores,
-            active: Array1::from_elem(scores.dim().0, true),
-            max_treatment_utils: scores.sum_axis(Axis(0)),
+            ctx: ctx,
+            active: Array1::from_elem(ctx.scores.dim().0, true),
+            n_active: ctx.scores.dim().0,
+            size: ctx.sizes.sum(),
+            population: ctx.weights.sum(),
+            max_treatment_utils: ctx.scores.sum_axis(Axis(0)),
+            ineligible: match &ctx.ineligible {
+                Some(ineligible) => ineligible.sum_axis(Axis(0)),
+                None => Array1::zeros(ctx.scores.dim().1),
+            },
+            upper_bound: ctx.row_max.sum(),
         };
 
         out
@@ -87,178 +435,982 @@ impl<'a> TreeSearcher<'a> {
 
     fn add(&mut self, index: usize) {
         self.active[index] = true;
-        self.max_treatment_utils += &self.scores.index_axis(Axis(0), index);
+        self.n_active += 1;
+        self.size += self.ctx.sizes[index];
+        self.population += self.ctx.weights[index];
+        self.max_treatment_utils += &self.ctx.scores.index_axis(Axis(0), index);
+        if let Some(ineligible) = &self.ctx.ineligible {
+            self.ineligible += &ineligible.index_axis(Axis(0), index);
+        }
+        self.upper_bound += self.ctx.row_max[index];
     }
 
     fn remove(&mut self, index: usize) {
         self.active[index] = false;
-        self.max_treatment_utils -= &self.scores.index_axis(Axis(0), index);
+        self.n_active -= 1;
+        self.size -= self.ctx.sizes[index];
+        self.population -= self.ctx.weights[index];
+        self.max_treatment_utils -= &self.ctx.scores.index_axis(Axis(0), index);
+        if let Some(ineligible) = &self.ctx.ineligible {
+            self.ineligible -= &ineligible.index_axis(Axis(0), index);
+        }
+        self.upper_bound -= self.ctx.row_max[index];
     }
 
-    // Search Single Split. Direct Analogue of the algorithm from the paper, but the rewards from
-    // assigning every unit a treatment are already calculated, so no arrays are needed.
-    fn search_single_split(&self) -> Node {
-        let nd: usize = self.scores.dim().1;
-        let np: usize = self.sets.len();
+    // Applies one step of a sweep along `dim` to the two sides of the cut
+    fn move_bundle(&self, dim: usize, step: SplitStep, sets_l: &mut Self, sets_r: &mut Self) {
+        for index in &self.ctx.sets[dim][step.bundle].indexes {
+            if self.active[*index] {
+                if step.to_left {
+                    sets_l.add(*index);
+                    sets_r.remove(*index);
+                } else {
+                    sets_r.add(*index);
+                    sets_l.remove(*index);
+                }
+            }
+        }
+    }
 
-        let mut best_r_leaf = Node::new_leaf(OrderedFloat(-f64::INFINITY), 0);
-        let mut best_l_leaf = Node::new_leaf(OrderedFloat(-f64::INFINITY), 0);
+    // Splits the active observations between the children of `tree`, a branch of a fitted tree,
+    // given their covariates in the rows of `x`
+    fn route(&self, tree: &Node, x: ArrayView2<OrderedFloat<f64>>) -> (Self, Self) {
+        let mut sets_r = self.clone();
+        let mut sets_l = Self::new_empty(self.ctx);
+        for (index, active) in self.active.iter().enumerate() {
+            if *active && tree.sends_left(x.row(index)) {
+                sets_l.add(index);
+                sets_r.remove(index);
+            }
+        }
+        (sets_l, sets_r)
+    }
 
-        let mut best_axis: usize = 0;
-        let mut best_cut_point: OrderedFloat<f64> = OrderedFloat(0.0);
+    // Leaf assigning every active unit the single best action. Used when a node is too small to be
+    // split without violating `min_node_size`. None if the eligibility mask allows no action here.
+    fn best_leaf(&self) -> Option<Node> {
+        let idx =
+            self.ctx
+                .best_action(&self.max_treatment_utils, &self.ineligible, self.n_active)?;
+        Some(self.ctx.leaf(self.max_treatment_utils[idx], idx))
+    }
+
+    // Search Single Split. Direct Analogue of the algorithm from the paper, but the rewards from
+    // assigning every unit a treatment are already calculated, so no arrays are needed. When called
+    // on the root (`top`), each finished axis is reported to the monitor as top-level progress.
+    // Both children of a split need a size of at least `min_node_size`, as in policytree, and cuts
+    // leaving a side where the eligibility mask allows no action are skipped.
+    fn search_single_split(&self, top: bool) -> Option<Node> {
+        let nd: usize = self.ctx.scores.dim().1;
+        let np: usize = self.ctx.sets.len();
+
+        let mut best: Option<(Node, Node, usize, usize, bool)> = None;
+        let mut best_reward = OrderedFloat(-f64::INFINITY);
+        let mut n_cuts: usize = 0;
 
         for p in 0..np {
-            let mut current_l_rewards = Array1::from_elem(nd, OrderedFloat(0.0));
-            let mut current_r_rewards = self.max_treatment_utils.clone();
-
-            for bundle in self.sets[p].iter() {
-                for row_idx in bundle.indexes.iter() {
-                    if self.active[*row_idx] {
-                        current_l_rewards += &self.scores.index_axis(Axis(0), *row_idx);
-                        current_r_rewards -= &self.scores.index_axis(Axis(0), *row_idx);
+            if self.ctx.monitor.should_stop() {
+                break;
+            }
+
+            for &missing_left in self.ctx.missing_directions(p) {
+                let mut left = SideTotals::new_empty(nd);
+                let mut right = SideTotals::new_full(self);
+
+                if missing_left {
+                    for row_idx in self.ctx.missing(p) {
+                        if self.active[*row_idx] {
+                            left.add(self.ctx, *row_idx);
+                            right.remove(self.ctx, *row_idx);
+                        }
                     }
                 }
+                let n_missing_left = left.n_units;
+
+                for (k, step) in self.ctx.steps[p].iter().enumerate() {
+                    for row_idx in self.ctx.sets[p][step.bundle].indexes.iter() {
+                        if self.active[*row_idx] {
+                            if step.to_left {
+                                left.add(self.ctx, *row_idx);
+                                right.remove(self.ctx, *row_idx);
+                            } else {
+                                right.add(self.ctx, *row_idx);
+                                left.remove(self.ctx, *row_idx);
+                            }
+                        }
+                    }
+                    n_cuts += 1;
+
+                    // Once the right side is too small, every later cut point on a numeric axis is
+                    // too small as well, so stop; along a categorical Gray-code walk the right side
+                    // can grow again, so keep going
+                    if right.size < self.ctx.min_node_size {
+                        if self.ctx.monotone(p) {
+                            break;
+                        }
+                        continue;
+                    }
+                    if left.size < self.ctx.min_node_size {
+                        continue;
+                    }
+                    if !self.ctx.on_split_step(p, left.n_units - n_missing_left) {
+                        continue;
+                    }
 
-                let current_l_idx = argmax(current_l_rewards.iter()).unwrap();
-                let current_r_idx = argmax(current_r_rewards.iter()).unwrap();
+                    let leaf_l = match left.best_leaf(self.ctx) {
+                        Some(leaf) => leaf,
+                        None => continue,
+                    };
+                    let leaf_r = match right.best_leaf(self.ctx) {
+                        Some(leaf) => leaf,
+                        None => continue,
+                    };
+
+                    if leaf_l.reward + leaf_r.reward > best_reward {
+                        best_reward = leaf_l.reward + leaf_r.reward;
+                        best = Some((leaf_l, leaf_r, p, k, missing_left));
+                    }
+                }
 
-                let current_l_reward = current_l_rewards[current_l_idx];
-                let current_r_reward = current_r_rewards[current_r_idx];
+                if top {
+                    self.ctx.monitor.top_cuts_finished(self.ctx.steps[p].len());
+                }
+            }
 
-                if (current_l_reward + current_r_reward) > (best_l_leaf.reward + best_r_leaf.reward)
-                {
-                    best_axis = p;
-                    best_cut_point = bundle.cut_point;
+            if top {
+                self.ctx.monitor.dimension_finished();
+            }
+        }
 
-                    best_l_leaf.reward = current_l_reward;
-                    best_r_leaf.reward = current_r_reward;
+        self.ctx.monitor.cuts_processed(n_cuts);
 
-                    best_l_leaf.action = Some(current_l_idx);
-                    best_r_leaf.action = Some(current_r_idx);
-                }
+        match best {
+            Some((leaf_l, leaf_r, axis, step, missing_left)) => Some(
+                self.ctx
+                    .branch(leaf_l, leaf_r, axis, step)
+                    .with_missing_left(missing_left),
+            ),
+            None => self.best_leaf(),
+        }
+    }
+
+    // Single dimension recursive search. Runs an exhaustive search, but is only able to consider
+    // splits along one axis in the top node. Only returns a tree if it beats `incumbent`, the best
+    // reward found so far elsewhere, which lets the bound pruning skip subtrees that cannot beat it.
+    // On the root (`top`) it is used for parallelization with Rayon, so it is also where top-level
+    // progress is reported. Large enough axes are split into chunks of cut points searched in
+    // parallel; of equally good splits the one with the lowest cut point wins, exactly as in a
+    // serial search.
+    fn single_dimension_recursive_search(
+        &self,
+        dim: usize,
+        depth: usize,
+        top: bool,
+        incumbent: OrderedFloat<f64>,
+    ) -> Option<Node> {
+        let n_cuts = self.ctx.steps[dim].len();
+        let n_chunks = self.n_parallel_chunks(n_cuts);
+
+        let mut best_tree: Option<Node> = None;
+        for &missing_left in self.ctx.missing_directions(dim) {
+            let incumbent = match &best_tree {
+                Some(tree) => tree.reward,
+                None => incumbent,
+            };
+
+            let tree = if n_chunks <= 1 {
+                self.search_cut_range(dim, depth, top, incumbent, 0..n_cuts, missing_left)
+            } else {
+                let chunk_size = n_cuts.div_ceil(n_chunks);
+                (0..n_chunks)
+                    .into_par_iter()
+                    .filter_map(|chunk| {
+                        let start = (chunk * chunk_size).min(n_cuts);
+                        let end = (start + chunk_size).min(n_cuts);
+                        self.search_cut_range(dim, depth, top, incumbent, start..end, missing_left)
+                    })
+                    .reduce_with(first_best)
+            };
+
+            if tree.is_some() {
+                best_tree = tree;
             }
         }
 
-        Node::new_branch(best_l_leaf, best_r_leaf, best_axis, best_cut_point)
-    }
+        if top {
+            self.ctx.monitor.dimension_finished();
+        }
 
-    // Single dimension recursive search. Runs an exhaustive search, but is only able to consider splits along one axis in the top node.
-    // Used for paralleization with Rayon.
-    fn single_dimension_recursive_search(&self, dim: usize, depth: usize) -> Node {
-        let mut best_r_tree = Node::new_leaf(OrderedFloat(-f64::INFINITY), 0);
-        let mut best_l_tree = Node::new_leaf(OrderedFloat(-f64::INFINITY), 0);
+        best_tree
+    }
 
-        let mut best_split_point: OrderedFloat<f64> = OrderedFloat(0.0);
-        let mut best_reward: OrderedFloat<f64> = OrderedFloat(-f64::INFINITY);
+    // Searches the cut points `cuts` along one axis, with the rows missing the covariate on the left
+    // if `missing_left` and on the right otherwise. Both sides are first brought to the state just
+    // before the first cut point by moving the observations in the same order a serial pass over
+    // the axis would, so the rewards (and so the result) don't depend on how the axis was chunked.
+    fn search_cut_range(
+        &self,
+        dim: usize,
+        depth: usize,
+        top: bool,
+        incumbent: OrderedFloat<f64>,
+        cuts: Range<usize>,
+        missing_left: bool,
+    ) -> Option<Node> {
+        let (start, end) = (cuts.start, cuts.end);
+        let mut best: Option<(Node, Node, usize)> = None;
+        let mut best_reward: OrderedFloat<f64> = incumbent;
 
         let mut sets_r = self.clone();
-        let mut sets_l = Self::new_empty(self.sets, self.scores);
+        let mut sets_l = Self::new_empty(self.ctx);
+
+        if missing_left {
+            for index in self.ctx.missing(dim) {
+                if self.active[*index] {
+                    sets_l.add(*index);
+                    sets_r.remove(*index);
+                }
+            }
+        }
+        let n_missing_left = sets_l.n_active;
+
+        for step in &self.ctx.steps[dim][..start] {
+            self.move_bundle(dim, *step, &mut sets_l, &mut sets_r);
+        }
+
+        for (i, step) in self.ctx.steps[dim][start..end].iter().enumerate() {
+            let n_left_over = end - start - i;
 
-        for bundle in &sets_r.sets[dim] {
-            let cut_point = bundle.cut_point;
+            if self.ctx.monitor.should_stop() {
+                break;
+            }
+
+            // Giving every unit its own best action is the best any split of this node could do.
+            // Once that can't beat the incumbent, no cut point left in this range is worth a look.
+            if self.ctx.bound_pruning && !may_improve(self.upper_bound, best_reward) {
+                self.ctx.monitor.subtrees_pruned(2 * n_left_over);
+                if top {
+                    self.ctx.monitor.top_cuts_finished(n_left_over);
+                }
+                break;
+            }
 
-            for index in &bundle.indexes {
-                sets_l.add(*index);
-                sets_r.remove(*index);
+            if top {
+                self.ctx.monitor.top_cuts_finished(1);
+            }
+            self.ctx.monitor.cuts_processed(1);
+
+            self.move_bundle(dim, *step, &mut sets_l, &mut sets_r);
+
+            if sets_r.size < self.ctx.min_node_size {
+                if !self.ctx.monotone(dim) {
+                    continue;
+                }
+                if top {
+                    self.ctx.monitor.top_cuts_finished(n_left_over - 1);
+                }
+                break;
+            }
+            if sets_l.size < self.ctx.min_node_size {
+                continue;
+            }
+            if !self
+                .ctx
+                .on_split_step(dim, sets_l.n_active - n_missing_left)
+            {
+                continue;
             }
 
-            let tree_l = sets_l.recursive_tree_search(depth - 1, false);
-            let tree_r = sets_r.recursive_tree_search(depth - 1, false);
+            // Each side only has to be searched for trees that could still lift the split above
+            // `best_reward`, given the most the other side could contribute
+            let tree_l = match sets_l.recursive_tree_search(
+                depth - 1,
+                false,
+                self.threshold(best_reward, sets_r.upper_bound),
+            ) {
+                Some(tree) => tree,
+                None => continue,
+            };
+            let tree_r = match sets_r.recursive_tree_search(
+                depth - 1,
+                false,
+                self.threshold(best_reward, tree_l.reward),
+            ) {
+                Some(tree) => tree,
+                None => continue,
+            };
 
             let current_reward = tree_l.reward + tree_r.reward;
 
             if current_reward > best_reward {
-                best_l_tree = tree_l;
-                best_r_tree = tree_r;
                 best_reward = current_reward;
-                best_split_point = cut_point;
+                best = Some((tree_l, tree_r, start + i));
             }
         }
 
-        return Node::new_branch(best_l_tree, best_r_tree, dim, best_split_point);
+        best.map(|(tree_l, tree_r, step)| {
+            self.ctx
+                .branch(tree_l, tree_r, dim, step)
+                .with_missing_left(missing_left)
+        })
+    }
+
+    // Number of chunks to split the cut points of one axis into for a parallel search. Each cut
+    // point costs at least a pass over the active observations, so the axis is only split when
+    // that adds up to enough work to pay for the tasks; small subproblems stay serial.
+    fn n_parallel_chunks(&self, n_cuts: usize) -> usize {
+        let n_threads = rayon::current_num_threads();
+        if n_threads == 1 || self.n_active * n_cuts < PARALLEL_MIN_WORK {
+            return 1;
+        }
+        (CHUNKS_PER_THREAD * n_threads).min(n_cuts)
     }
 
-    // Proper recursive tree search. Taken almost directly from policytree package.
-    fn recursive_tree_search(&self, depth: usize, top: bool) -> Node {
-        if depth == 1 {
-            return self.search_single_split();
+    // Incumbent for one side of a split, given that the other side brings in at most `other`: the
+    // side has to beat `best_reward - other` for the split to beat `best_reward`. Without bound
+    // pruning every subtree is searched in full.
+    fn threshold(
+        &self,
+        best_reward: OrderedFloat<f64>,
+        other: OrderedFloat<f64>,
+    ) -> OrderedFloat<f64> {
+        if !self.ctx.bound_pruning {
+            return OrderedFloat(-f64::INFINITY);
+        }
+        let threshold = best_reward.0 - other.0;
+        OrderedFloat(threshold - BOUND_TOLERANCE * (1.0 + threshold.abs()))
+    }
+
+    // Proper recursive tree search. Taken almost directly from policytree package. Returns the best
+    // tree of the given depth over the active observations, or None if it cannot beat `incumbent`.
+    // With bound pruning, a subtree whose upper bound already rules that out is never searched.
+    fn recursive_tree_search(
+        &self,
+        depth: usize,
+        top: bool,
+        incumbent: OrderedFloat<f64>,
+    ) -> Option<Node> {
+        if self.ctx.bound_pruning && !may_improve(self.upper_bound, incumbent) {
+            self.ctx.monitor.subtrees_pruned(1);
+            return None;
+        }
+
+        let best_tree = if depth == 0 {
+            None
+        } else if depth == 1 {
+            self.search_single_split(top)
         } else if top {
-            let np: usize = self.sets.len();
+            let np: usize = self.ctx.sets.len();
 
             (0..np)
                 .into_par_iter()
-                .map(|dim| self.single_dimension_recursive_search(dim, depth))
-                .max()
-                .unwrap()
+                .filter_map(|dim| {
+                    self.single_dimension_recursive_search(dim, depth, true, incumbent)
+                })
+                .reduce_with(first_best)
         } else {
-            let np: usize = self.sets.len();
+            let np: usize = self.ctx.sets.len();
 
-            let mut best_r_tree = Node::new_leaf(OrderedFloat(-f64::INFINITY), 0);
-            let mut best_l_tree = Node::new_leaf(OrderedFloat(-f64::INFINITY), 0);
-            let mut best_split_axis: usize = 0;
-            let mut best_split_point: OrderedFloat<f64> = OrderedFloat(0.0);
-            let mut best_reward: OrderedFloat<f64> = OrderedFloat(-f64::INFINITY);
+            let mut best_tree: Option<Node> = None;
 
             for p in 0..np {
-                let mut sets_r = self.clone();
-                let mut sets_l = Self::new_empty(self.sets, self.scores);
+                let best_reward = match &best_tree {
+                    Some(tree) => tree.reward,
+                    None => incumbent,
+                };
 
-                for bundle in sets_r.sets[p].iter() {
-                    let cut_point = bundle.cut_point;
+                if let Some(tree) =
+                    self.single_dimension_recursive_search(p, depth, false, best_reward)
+                {
+                    best_tree = Some(tree);
+                }
+            }
 
-                    for index in &bundle.indexes {
-                        if self.active[*index] {
-                            sets_l.add(*index);
-                            sets_r.remove(*index);
-                        }
-                    }
+            best_tree
+        };
 
-                    let tree_l = sets_l.recursive_tree_search(depth - 1, false);
-                    let tree_r = sets_r.recursive_tree_search(depth - 1, false);
+        let best_tree = match best_tree {
+            Some(tree) if self.ctx.leaves_compete() => match self.best_leaf() {
+                Some(leaf) if leaf.reward > tree.reward => Some(leaf),
+                _ => Some(tree),
+            },
+            best_tree => best_tree,
+        };
 
-                    let current_reward = tree_l.reward + tree_r.reward;
+        // Too small to split, or no split beats the incumbent (a leaf only might if leaves compete)
+        best_tree
+            .or_else(|| self.best_leaf())
+            .filter(|tree| tree.reward > incumbent)
+    }
+}
 
-                    if current_reward > best_reward {
-                        best_l_tree = tree_l;
-                        best_r_tree = tree_r;
-                        best_reward = current_reward;
-                        best_split_axis = p;
-                        best_split_point = cut_point;
-                    }
-                }
-            }
+// Reduction used to combine the results of parallel searches, which rayon applies in the order of
+// the searched items. Keeps the earlier of two equally good trees, like a serial search would.
+fn first_best(first: Node, second: Node) -> Node {
+    if second.reward > first.reward {
+        second
+    } else {
+        first
+    }
+}
+
+// Whether a subtree whose reward can be at most `bound` might still beat `best_reward`. Bounds are
+// updated incrementally as observations move between searchers, so they are compared with a little
+// slack for rounding error; pruning must never throw away the optimal tree.
+fn may_improve(bound: OrderedFloat<f64>, best_reward: OrderedFloat<f64>) -> bool {
+    bound.0 + BOUND_TOLERANCE * (1.0 + bound.0.abs()) > best_reward.0
+}
 
-            return Node::new_branch(best_l_tree, best_r_tree, best_split_axis, best_split_point);
+// Fails if a categorical covariate has more levels than subsets of them can be searched for
+fn check_categorical_levels(sets: &[Vec<ObservationBundle>], categorical: &[bool]) -> Result<()> {
+    for (dim, set) in sets.iter().enumerate() {
+        if categorical[dim] && set.len() > MAX_CATEGORICAL_LEVELS {
+            return Err(Error::Other(format!(
+                "Categorical covariate {} has {} levels, at most {} are supported",
+                dim + 1,
+                set.len(),
+                MAX_CATEGORICAL_LEVELS
+            )));
         }
     }
+    Ok(())
+}
+
+// Number of cut points the top node of a search goes through, for progress reports. Axes with
+// missing values are searched twice, once with them on each side.
+fn count_top_cuts(
+    sets: &[Vec<ObservationBundle>],
+    missing: &[Vec<usize>],
+    categorical: &[bool],
+) -> usize {
+    sets.iter()
+        .zip(missing.iter())
+        .zip(categorical.iter())
+        .map(|((set, rows), is_categorical)| {
+            let n_steps = if *is_categorical {
+                categorical_steps(set.len()).len()
+            } else {
+                set.len()
+            };
+            if rows.is_empty() {
+                n_steps
+            } else {
+                2 * n_steps
+            }
+        })
+        .sum()
+}
+
+// Builds the thread pool a single call from R runs its parallel work on, rather than rayon's global
+// pool, so the number of threads can be chosen per call. `num_threads` = 0 picks rayon's default
+// (the RAYON_NUM_THREADS variable, or one thread per core), and 1 makes the search fully serial.
+fn build_thread_pool(num_threads: i64) -> Result<rayon::ThreadPool> {
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(num_threads as usize)
+        .build()
+        .map_err(|err| Error::Other(format!("Unable to start thread pool: {}", err)))
 }
 
-// function called from R. Process data into matrix of OrderedFloats, then run search.
+// function called from R. Takes the covariates already sorted by `rust_sorted_covariates`, so they
+// can be shared by many searches, and searches over the rows marked in `rows_robj` (a 0/1 vector,
+// or NULL for all of them); Gamma and the other inputs have a row for every row of X. The other
+// settings come in the named list `options_robj`, see `SearchOptions`, and are described below by
+// their names there. The search runs off the main thread so that, with `verbose`, progress can be
+// printed to the R console while it is going, and so that the main thread can keep checking whether
+// the user pressed Ctrl-C. With a finite `time_limit` (in seconds) the search stops once the budget
+// is spent and returns the best tree found so far, flagged as not certified optimal, along with the
+// share of the top-level cut points that were searched. `bound_pruning` skips subtrees that
+// provably cannot beat the best tree found so far, without changing the result; the number of
+// skipped subtrees is reported. The search runs on its own pool of `num_threads` threads, see
+// `build_thread_pool`. With `prune`, branches whose leaves all recommend the same action are
+// collapsed before the tree is returned. Each row of Gamma counts `weights` times towards the
+// rewards, and with `weighted_size` the weights also count towards `min_node_size` in place of the
+// number of units. `capacity` gives the largest share of the (weighted) population each action may
+// be assigned, or with `capacity_counts` the largest number of (unweighted) units, infinite where
+// there is no limit; any finite limit switches to the constrained
+// search in `constrained`, which fails if no tree satisfies the limits. `eligible_robj` is either
+// NULL or a 0/1 matrix shaped like Gamma marking the actions each unit is eligible for; leaves may
+// then only recommend actions that at least `eligibility_fraction` of their units are eligible for.
+// Missing values (NA) in X are allowed, and every split learns which side the rows missing its
+// covariate go to. Columns marked categorical when sorting X are split by subsets of their levels,
+// see `categorical`. With a positive `penalty` the search maximises the total reward minus
+// `penalty` times the number of leaves, and with `pruning_path` the cost-complexity pruning path of
+// the tree found is returned as well, see `complexity`. With a positive `top_k` the `top_k` best
+// distinct trees are returned too, see `ranked`, and the tree returned is the first of them. With a
+// positive `rashomon_max_trees`, a second search collects every distinct tree whose reward is
+// within `rashomon_epsilon` of the best one (a share of its absolute value with
+// `rashomon_relative`), up to `rashomon_max_trees` of them. `statistics` gives the training
+// observations reaching each node of the tree returned and their rewards, see `node_statistics`.
 #[extendr]
-fn rust_exhaustive_tree(x_robj: Robj, gamma_robj: Robj, depth: i64) -> List {
-    let x_mat = <ArrayView2<f64>>::from_robj(&x_robj)
+fn rust_exhaustive_tree(
+    covariates_robj: Robj,
+    gamma_robj: Robj,
+    weights_robj: Robj,
+    rows_robj: Robj,
+    eligible_robj: Robj,
+    options_robj: Robj,
+) -> Result<List> {
+    let options = SearchOptions::from_list(&options_robj)?;
+    let pool = build_thread_pool(options.num_threads)?;
+
+    let pointer = SortedCovariates::from_pointer(&covariates_robj)?;
+    // Only the covariates themselves are shared with the search threads, never the R object
+    let covariates: &SortedCovariates = &pointer;
+    let weights = <ArrayView1<f64>>::from_robj(&weights_robj).unwrap();
+    let scores_mat = <ArrayView2<f64>>::from_robj(&gamma_robj)
         .unwrap()
         .to_owned()
         .map(|x| OrderedFloat(*x));
+    let scores_mat = &scores_mat * &weights.map(|w| OrderedFloat(*w)).insert_axis(Axis(1));
+    let rows: Option<Vec<bool>> = if rows_robj.is_null() {
+        None
+    } else {
+        let rows = <ArrayView1<f64>>::from_robj(&rows_robj).unwrap();
+        Some(rows.iter().map(|x| *x != 0.0).collect())
+    };
+    let ineligible = if eligible_robj.is_null() {
+        None
+    } else {
+        let eligible = <ArrayView2<f64>>::from_robj(&eligible_robj).unwrap();
+        Some(eligible.map(|x| if *x == 0.0 { 1 } else { 0 }))
+    };
+    let sizes = if options.weighted_size {
+        weights.to_owned()
+    } else {
+        Array1::from_elem(weights.len(), 1.0)
+    };
+
+    let sets = &covariates.sets;
+    let x_mat = &covariates.x;
+    let total_top_cuts = count_top_cuts(sets, &covariates.missing, &covariates.categorical);
+    let depth = options.depth;
+    let penalty = options.penalty;
+    let rashomon_max_trees = options.rashomon_max_trees;
+
+    // The Rashomon set takes a second pass over the top-level cut points
+    let n_passes = if rashomon_max_trees > 0 { 2 } else { 1 };
+    let monitor = SearchMonitor::new(
+        n_passes * sets.len(),
+        n_passes * total_top_cuts,
+        options.time_limit,
+    );
+    let mut ctx = SearchContext::new(
+        sets,
+        scores_mat.view(),
+        weights,
+        sizes.view(),
+        options.min_node_size,
+        options.bound_pruning,
+        &monitor,
+    )
+    .with_missing(&covariates.missing)
+    .with_categorical(&covariates.categorical)
+    .with_split_step(covariates.split_step)
+    .with_leaf_penalty(penalty);
+    if let Some(ineligible) = &ineligible {
+        ctx = ctx.with_eligibility(ineligible.view(), options.eligibility_fraction);
+    }
+
+    let mut searcher = TreeSearcher::new_full(&ctx);
+    if let Some(rows) = &rows {
+        for (index, active) in rows.iter().enumerate() {
+            if !active {
+                searcher.remove(index);
+            }
+        }
+    }
+    let searcher = searcher;
+    let capacity = Capacity::new(
+        &options.capacity,
+        options.capacity_counts,
+        searcher.population,
+        searcher.n_active,
+    );
+
+    let ((search_results, mut ranked), mut rashomon) = run_monitored(
+        &monitor,
+        || {
+            pool.install(|| {
+                let best = if !capacity.is_empty() {
+                    (searcher.best_constrained_tree(depth, &capacity), Vec::new())
+                } else if options.top_k > 0 {
+                    let trees = searcher.best_trees(depth, options.top_k);
+                    (trees.first().cloned(), trees)
+                } else {
+                    let tree =
+                        searcher.recursive_tree_search(depth, true, OrderedFloat(-f64::INFINITY));
+                    (tree.or_else(|| searcher.best_leaf()), Vec::new())
+                };
+
+                let rashomon = match &best.0 {
+                    Some(tree) if rashomon_max_trees > 0 => {
+                        let slack = if options.rashomon_relative {
+                            options.rashomon_epsilon * tree.reward.0.abs()
+                        } else {
+                            options.rashomon_epsilon
+                        };
+                        // One more than the cap shows whether the set had to be cut short
+                        searcher.rashomon_set(depth, rashomon_max_trees + 1, tree.reward - slack)
+                    }
+                    _ => Vec::new(),
+                };
+                (best, rashomon)
+            })
+        },
+        user_interrupted,
+        |progress| {
+            if options.verbose {
+                rprintln!("{}", progress);
+            }
+        },
+    );
+
+    if monitor.cancelled() {
+        return Err(Error::Other("Tree search interrupted by user".to_string()));
+    }
+
+    let mut search_results = match search_results {
+        Some(tree) => tree,
+        None => {
+            let constraints = match (capacity.is_empty(), ineligible.is_none()) {
+                (false, true) => "capacity constraints",
+                (true, false) => "eligibility constraints",
+                _ => "capacity and eligibility constraints",
+            };
+            let message = if monitor.timed_out() {
+                format!(
+                    "Time limit reached before any tree satisfying the {} was found",
+                    constraints
+                )
+            } else {
+                format!("No tree satisfies the {}", constraints)
+            };
+            return Err(Error::Other(message));
+        }
+    };
+
+    let progress = monitor.progress();
+    let complete = !monitor.timed_out();
+    if options.verbose {
+        if complete {
+            rprintln!("Search finished in {:.1}s", progress.elapsed.as_secs_f64());
+        } else {
+            rprintln!("Time limit reached: {}", progress);
+        }
+    }
+
+    search_results.remove_leaf_penalty(OrderedFloat(penalty));
+    let rashomon_truncated = rashomon.len() > rashomon_max_trees;
+    rashomon.truncate(rashomon_max_trees);
+    for tree in ranked.iter_mut().chain(rashomon.iter_mut()) {
+        tree.remove_leaf_penalty(OrderedFloat(penalty));
+    }
+    if options.prune {
+        search_results.prune();
+    }
+
+    let ranking_list = |trees: &[Node]| -> Vec<List> {
+        trees
+            .iter()
+            .map(|tree| {
+                list!(
+                    reward = f64::from(tree.reward),
+                    nodes = tree.r_representation()
+                )
+            })
+            .collect()
+    };
+
+    let path: Vec<List> = if options.pruning_path {
+        searcher
+            .pruning_path(&search_results, x_mat.view())
+            .iter()
+            .map(|(penalty, tree)| {
+                list!(
+                    penalty = *penalty,
+                    reward = f64::from(tree.reward),
+                    n_leaves = tree.n_leaves(),
+                    nodes = tree.r_representation()
+                )
+            })
+            .collect()
+    } else {
+        Vec::new()
+    };
+
+    Ok(list!(
+        nodes = search_results.r_representation(),
+        statistics = searcher.node_statistics(&search_results, x_mat.view()),
+        complete = complete,
+        coverage = if complete { 1.0 } else { progress.fraction() },
+        pruned = progress.pruned,
+        path = List::from_values(path),
+        ranked = List::from_values(ranking_list(&ranked)),
+        rashomon = List::from_values(ranking_list(&rashomon)),
+        rashomon_truncated = rashomon_truncated
+    ))
+}
+
+// Predicts with a fitted tree, laid out as described in `FlatTree`, for every row of the covariate
+// matrix `x_robj`, on a pool of `num_threads` threads (see `build_thread_pool`). Returns the
+// recommended actions and the ids of the leaves the rows fall in.
+#[extendr]
+fn rust_predict(
+    tree_robj: Robj,
+    levels_robj: Robj,
+    x_robj: Robj,
+    num_threads: i64,
+) -> Result<List> {
+    let pool = build_thread_pool(num_threads)?;
+
+    let nodes = <ArrayView2<f64>>::from_robj(&tree_robj).unwrap();
+    let levels = <ArrayView1<f64>>::from_robj(&levels_robj).unwrap();
+    let x = <ArrayView2<f64>>::from_robj(&x_robj).unwrap();
+
+    let tree = FlatTree::new(nodes, levels);
+    let (actions, leaves) = pool.install(|| tree.predict(x));
+
+    Ok(list!(action = actions, node = leaves))
+}
+
+// Estimates the value of a fitted tree, laid out as described in `FlatTree`, on held-out covariates
+// `x_robj` and rewards `gamma_robj`, see `FlatTree::policy_value`. Returns the estimated mean
+// reward and its standard error, and the same for the difference against each single action.
+#[extendr]
+fn rust_policy_value(
+    tree_robj: Robj,
+    levels_robj: Robj,
+    x_robj: Robj,
+    gamma_robj: Robj,
+    num_threads: i64,
+) -> Result<List> {
+    let pool = build_thread_pool(num_threads)?;
+
+    let nodes = <ArrayView2<f64>>::from_robj(&tree_robj).unwrap();
+    let levels = <ArrayView1<f64>>::from_robj(&levels_robj).unwrap();
+    let x = <ArrayView2<f64>>::from_robj(&x_robj).unwrap();
+    let gamma = <ArrayView2<f64>>::from_robj(&gamma_robj).unwrap();
+
+    let tree = FlatTree::new(nodes, levels);
+    let (value, differences) = pool.install(|| tree.policy_value(x, gamma));
+
+    Ok(list!(
+        estimate = value.estimate,
+        std_err = value.std_err,
+        difference_estimates = differences.iter().map(|d| d.estimate).collect::<Vec<f64>>(),
+        difference_std_errs = differences.iter().map(|d| d.std_err).collect::<Vec<f64>>()
+    ))
+}
+
+// Sorts the covariates `x_robj` once for any number of searches, see `SortedCovariates`, and
+// returns them to R as an external pointer, which `rust_exhaustive_tree` and `rust_cross_validate`
+// take in place of the covariates. `split_step` and `categorical_robj` are as described there.
+#[extendr]
+fn rust_sorted_covariates(x_robj: Robj, split_step: i64, categorical_robj: Robj) -> Result<Robj> {
+    let x = <ArrayView2<f64>>::from_robj(&x_robj).unwrap();
+    let categorical: Vec<bool> = <ArrayView1<f64>>::from_robj(&categorical_robj)
+        .unwrap()
+        .iter()
+        .map(|x| *x != 0.0)
+        .collect();
+
+    let covariates = SortedCovariates::new(x, split_step as usize, categorical)?;
+    Ok(ExternalPtr::new(covariates).into())
+}
+
+// Cross-validates the depth and leaf penalty of the tree, see `cross_validation`. `folds_robj`
+// gives the (1-based) fold of every row of X, and every combination of `depths_robj` and
+// `penalties_robj` is searched on each set of training folds, with the covariates of
+// `rust_exhaustive_tree` and the options in `options_robj` that apply, see `SearchOptions`. Returns the summed out-of-fold reward of each combination (depth by
+// depth, then penalty) on each fold, one fold after the other. With `verbose`, progress over all
+// of the searches is printed every few seconds.
+#[extendr]
+fn rust_cross_validate(
+    covariates_robj: Robj,
+    gamma_robj: Robj,
+    weights_robj: Robj,
+    folds_robj: Robj,
+    depths_robj: Robj,
+    penalties_robj: Robj,
+    options_robj: Robj,
+) -> Result<List> {
+    let options = SearchOptions::from_list(&options_robj)?;
+    let pool = build_thread_pool(options.num_threads)?;
+
+    let pointer = SortedCovariates::from_pointer(&covariates_robj)?;
+    // Only the covariates themselves are shared with the search threads, never the R object
+    let covariates: &SortedCovariates = &pointer;
+    let weights = <ArrayView1<f64>>::from_robj(&weights_robj).unwrap();
     let scores_mat = <ArrayView2<f64>>::from_robj(&gamma_robj)
         .unwrap()
         .to_owned()
         .map(|x| OrderedFloat(*x));
+    let scores_mat = &scores_mat * &weights.map(|w| OrderedFloat(*w)).insert_axis(Axis(1));
+    let sizes = if options.weighted_size {
+        weights.to_owned()
+    } else {
+        Array1::from_elem(weights.len(), 1.0)
+    };
+    let folds: Vec<usize> = <ArrayView1<f64>>::from_robj(&folds_robj)
+        .unwrap()
+        .iter()
+        .map(|fold| *fold as usize - 1)
+        .collect();
+    let n_folds = folds.iter().max().map_or(0, |fold| fold + 1);
+    let depths: Vec<usize> = <ArrayView1<f64>>::from_robj(&depths_robj)
+        .unwrap()
+        .iter()
+        .map(|depth| *depth as usize)
+        .collect();
+    let penalties = <ArrayView1<f64>>::from_robj(&penalties_robj)
+        .unwrap()
+        .to_vec();
+    let sets = &covariates.sets;
+    let n_searches = n_folds * depths.len() * penalties.len();
+    let monitor = SearchMonitor::new(
+        n_searches * sets.len(),
+        n_searches * count_top_cuts(sets, &covariates.missing, &covariates.categorical),
+        None,
+    );
+    let ctx = SearchContext::new(
+        sets,
+        scores_mat.view(),
+        weights,
+        sizes.view(),
+        options.min_node_size,
+        options.bound_pruning,
+        &monitor,
+    )
+    .with_missing(&covariates.missing)
+    .with_categorical(&covariates.categorical)
+    .with_split_step(covariates.split_step);
+
+    let rewards = run_monitored(
+        &monitor,
+        || {
+            pool.install(|| {
+                ctx.cross_validate(covariates.x.view(), &folds, n_folds, &depths, &penalties)
+            })
+        },
+        user_interrupted,
+        |progress| {
+            if options.verbose {
+                rprintln!("{}", progress);
+            }
+        },
+    );
 
-    // let test = SortedSets::new_populated(x_mat.view(), scores_mat.view());
-    // let search_results = test.recursive_tree_search(depth as usize, true);
+    if monitor.cancelled() {
+        return Err(Error::Other("Tree search interrupted by user".to_string()));
+    }
 
-    let test = new_sorted_sets(x_mat.view());
+    let rewards: Vec<f64> = (0..n_folds)
+        .flat_map(|fold| rewards.iter().map(move |config| config[fold]))
+        .collect();
 
-    let searcher = TreeSearcher::new_full(&test, scores_mat.view());
+    Ok(list!(rewards = rewards))
+}
 
-    let search_results = searcher.recursive_tree_search(depth as usize, true);
+// Refits the tree on bootstrap (or subsample) replicates of the rows, see `bootstrap_trees`, with
+// the covariates sorted by `rust_sorted_covariates` and the options in `options_robj` that apply,
+// see `SearchOptions`.
+// `counts_robj` has a column for each replicate, giving the number of times each row is drawn.
+// Returns the tree of each replicate, pruned, and the action each tree recommends for every row of
+// X, one replicate after the other. With `verbose`, progress over all of the replicates is printed
+// every few seconds.
+#[extendr]
+fn rust_bootstrap(
+    covariates_robj: Robj,
+    gamma_robj: Robj,
+    weights_robj: Robj,
+    counts_robj: Robj,
+    options_robj: Robj,
+) -> Result<List> {
+    let options = SearchOptions::from_list(&options_robj)?;
+    let pool = build_thread_pool(options.num_threads)?;
+
+    let pointer = SortedCovariates::from_pointer(&covariates_robj)?;
+    // Only the covariates themselves are shared with the search threads, never the R object
+    let covariates: &SortedCovariates = &pointer;
+    let gamma = <ArrayView2<f64>>::from_robj(&gamma_robj).unwrap();
+    let weights = <ArrayView1<f64>>::from_robj(&weights_robj).unwrap();
+    let counts = <ArrayView2<f64>>::from_robj(&counts_robj).unwrap();
+
+    let n_replicates = counts.ncols();
+    let monitor = SearchMonitor::new(
+        n_replicates * covariates.sets.len(),
+        n_replicates
+            * count_top_cuts(
+                &covariates.sets,
+                &covariates.missing,
+                &covariates.categorical,
+            ),
+        None,
+    );
+
+    let trees = run_monitored(
+        &monitor,
+        || pool.install(|| covariates.bootstrap_trees(gamma, weights, counts, &options, &monitor)),
+        user_interrupted,
+        |progress| {
+            if options.verbose {
+                rprintln!("{}", progress);
+            }
+        },
+    );
 
-    // for _ in 0..depth {
-    //     search_results.prune();
-    // }
+    if monitor.cancelled() {
+        return Err(Error::Other("Tree search interrupted by user".to_string()));
+    }
+
+    // Branches whose leaves all recommend one action don't change the policy, so they don't count
+    // towards how often a covariate is split on
+    let mut trees = trees;
+    for tree in trees.iter_mut() {
+        tree.prune();
+    }
 
-    search_results.r_representation()
+    // Actions are 1-based in R
+    let actions: Vec<f64> = pool.install(|| {
+        trees
+            .par_iter()
+            .flat_map_iter(|tree| {
+                covariates
+                    .x
+                    .outer_iter()
+                    .map(move |x| (tree.action_for(x) + 1) as f64)
+            })
+            .collect()
+    });
+    let trees: Vec<List> = trees.iter().map(|tree| tree.r_representation()).collect();
+
+    Ok(list!(trees = List::from_values(trees), actions = actions))
+}
+
+// Builds doubly robust scores from outcomes `y_robj`, actions `w_robj` (1-based), and N×D matrices
+// of propensities and outcome-model predictions, clipping propensities below `clip`, see
+// `aipw_scores`, on a pool of `num_threads` threads (see `build_thread_pool`). Returns the scores,
+// column-major, and the number of units clipped for each action.
+#[extendr]
+fn rust_aipw_scores(
+    y_robj: Robj,
+    w_robj: Robj,
+    propensities_robj: Robj,
+    predictions_robj: Robj,
+    clip: f64,
+    num_threads: i64,
+) -> Result<List> {
+    let pool = build_thread_pool(num_threads)?;
+
+    let y = <ArrayView1<f64>>::from_robj(&y_robj).unwrap();
+    let w = <ArrayView1<f64>>::from_robj(&w_robj).unwrap();
+    let propensities = <ArrayView2<f64>>::from_robj(&propensities_robj).unwrap();
+    let predictions = <ArrayView2<f64>>::from_robj(&predictions_robj).unwrap();
+
+    let (scores, clipped) = pool.install(|| aipw_scores(y, w, propensities, predictions, clip));
+    let clipped: Vec<f64> = clipped.into_iter().map(|count| count as f64).collect();
+
+    Ok(list!(scores = scores, clipped = clipped))
 }
 
 // Macro to generate exports.
@@ -266,215 +1418,11 @@ fn rust_exhaustive_tree(x_robj: Robj, gamma_robj: Robj, depth: i64) -> List {
 // See corresponding C code in `entrypoint.c`.
 extendr_module! {
     mod sparsepolicytree;
+    fn rust_sorted_covariates;
     fn rust_exhaustive_tree;
+    fn rust_predict;
+    fn rust_policy_value;
+    fn rust_aipw_scores;
+    fn rust_cross_validate;
+    fn rust_bootstrap;
 }
-
-// #[derive(Debug, Clone)]
-// struct SortedSets<'a> {
-//     sets: Vec<BTreeMap<OrderedFloat<f64>, Vec<usize>>>,
-//     dataset: ArrayView2<'a, OrderedFloat<f64>>,
-//     scores: ArrayView2<'a, OrderedFloat<f64>>,
-//     max_treatment_utils: Array1<OrderedFloat<f64>>,
-// }
-
-// impl<'a> SortedSets<'a> {
-//     fn new_empty(
-//         dataset: ArrayView2<'a, OrderedFloat<f64>>,
-//         scores: ArrayView2<'a, OrderedFloat<f64>>,
-//     ) -> Self {
-//         let mut output = SortedSets {
-//             sets: Vec::new(),
-//             dataset: dataset,
-//             scores: scores,
-//             max_treatment_utils: Array1::from_elem(scores.dim().1, OrderedFloat(0.0)),
-//         };
-
-//         for _ in dataset.axis_iter(Axis(1)) {
-//             let tree: BTreeMap<OrderedFloat<f64>, Vec<usize>> = BTreeMap::new();
-//             output.sets.push(tree);
-//         }
-//         output
-//     }
-
-//     fn new_populated(
-//         dataset: ArrayView2<'a, OrderedFloat<f64>>,
-//         scores: ArrayView2<'a, OrderedFloat<f64>>,
-//     ) -> Self {
-//         let mut tree = Self::new_empty(dataset, scores);
-
-//         for i in 0..dataset.dim().0 {
-//             tree.add_by_index(i);
-//         }
-
-//         tree
-//     }
-
-//     fn remove_by_key(&mut self, starting_index: usize, key: &OrderedFloat<f64>) {
-//         let (_, row_idxes) = self.sets[starting_index]
-//             .remove_entry(key)
-//             .expect("Key Not Found While Removing");
-
-//         for i in row_idxes {
-//             for j in 0..self.dataset.dim().1 {
-//                 if j != starting_index {
-//                     let key = OrderedFloat(self.dataset[[i, j]]);
-//                     let arr: &mut Vec<usize> = self.sets[j].get_mut(&key).expect("Unable to find");
-//                     if arr.len() == 1 {
-//                         self.sets[j].remove(&key).expect("failed to remove!");
-//                     } else {
-//                         arr.retain(|x| x != &i);
-//                     }
-//                 }
-//             }
-//             self.max_treatment_utils -= &self.scores.slice(s![i, ..]);
-//         }
-//     }
-
-//     fn add_by_index(&mut self, index: usize) {
-//         let row = self.dataset.slice(s![index, ..]);
-//         for (i, entry) in row.iter().enumerate() {
-//             if !self.sets[i].contains_key(entry) {
-//                 self.sets[i].insert(*entry, vec![index]);
-//             } else {
-//                 let arr = self.sets[i].get_mut(entry).unwrap();
-//                 arr.push(index);
-//             }
-//         }
-//         self.max_treatment_utils += &self.scores.slice(s![index, ..]);
-//     }
-
-//     fn search_single_split(&self) -> Node {
-//         let nd: usize = self.scores.dim().1;
-//         let np: usize = self.sets.len();
-
-//         let mut best_r_leaf = Node::new_leaf(OrderedFloat(-999999999999.9), 0);
-//         let mut best_l_leaf = Node::new_leaf(OrderedFloat(-999999999999.9), 0);
-
-//         let mut best_axis: usize = 0;
-//         let mut best_cut_point: OrderedFloat<f64> = OrderedFloat(-999999999999.9);
-
-//         for p in 0..np {
-//             let mut current_l_rewards = Array1::from_elem(nd, OrderedFloat(0.0));
-//             let mut current_r_rewards = self.max_treatment_utils.clone();
-//             for (cut_point, row_indexes) in self.sets[p].iter() {
-//                 for row_idx in row_indexes {
-//                     current_l_rewards += &self.scores.slice(s![*row_idx, ..]);
-//                     current_r_rewards -= &self.scores.slice(s![*row_idx, ..]);
-//                 }
-
-//                 let current_l_idx = argmax(current_l_rewards.iter()).unwrap();
-//                 let current_r_idx = argmax(current_r_rewards.iter()).unwrap();
-
-//                 let current_l_reward = current_l_rewards[current_l_idx];
-//                 let current_r_reward = current_r_rewards[current_r_idx];
-
-//                 if (current_l_reward + current_r_reward) > (best_l_leaf.reward + best_r_leaf.reward)
-//                 {
-//                     best_axis = p;
-//                     best_cut_point = *cut_point;
-
-//                     best_l_leaf.reward = current_l_reward;
-//                     best_r_leaf.reward = current_r_reward;
-
-//                     best_l_leaf.action = Some(current_l_idx);
-//                     best_r_leaf.action = Some(current_r_idx);
-//                 }
-//             }
-//         }
-//         Node::new_branch(best_l_leaf, best_r_leaf, best_axis, best_cut_point)
-//     }
-
-//     fn single_dimension_recursive_search(&self, dim: usize, depth: usize) -> Node {
-//         let mut best_r_tree = Node::new_leaf(OrderedFloat(-999999999999.9), 0);
-//         let mut best_l_tree = Node::new_leaf(OrderedFloat(-999999999999.9), 0);
-
-//         let mut best_split_point: OrderedFloat<f64> = OrderedFloat(0.0);
-//         let mut best_reward: OrderedFloat<f64> = OrderedFloat(-99999999999.99);
-
-//         let mut sets_r = self.clone();
-//         let mut sets_l = Self::new_empty(self.dataset, self.scores);
-
-//         let n_cuts = sets_r.sets[dim].len();
-//         for _ in 0..(n_cuts) {
-//             let (cut_point, ids) = sets_r.sets[dim].first_key_value().unwrap();
-//             let cut_point = cut_point.clone();
-
-//             // TODO: This is extremely expensive. Try and cut it out
-
-//             for index in ids {
-//                 sets_l.add_by_index(*index);
-//             }
-
-//             sets_r.remove_by_key(dim, &cut_point);
-
-//             let tree_l = sets_l.recursive_tree_search(depth - 1, false);
-//             let tree_r = sets_r.recursive_tree_search(depth - 1, false);
-
-//             let current_reward = tree_l.reward + tree_r.reward;
-
-//             if current_reward > best_reward {
-//                 best_l_tree = tree_l;
-//                 best_r_tree = tree_r;
-//                 best_reward = current_reward;
-//                 best_split_point = cut_point;
-//             }
-//         }
-
-//         return Node::new_branch(best_l_tree, best_r_tree, dim, best_split_point);
-//     }
-
-//     fn recursive_tree_search(&self, depth: usize, top: bool) -> Node {
-//         if depth == 1 {
-//             return self.search_single_split();
-//         } else if top {
-//             let np: usize = self.sets.len();
-
-//             (0..np)
-//                 .into_par_iter()
-//                 .map(|dim| self.single_dimension_recursive_search(dim, depth))
-//                 .max()
-//                 .unwrap()
-//         } else {
-//             let np: usize = self.sets.len();
-
-//             let mut best_r_tree = Node::new_leaf(OrderedFloat(-999999999999.9), 0);
-//             let mut best_l_tree = Node::new_leaf(OrderedFloat(-999999999999.9), 0);
-//             let mut best_split_axis: usize = 0;
-//             let mut best_split_point: OrderedFloat<f64> = OrderedFloat(0.0);
-//             let mut best_reward: OrderedFloat<f64> = OrderedFloat(-99999999999.99);
-
-//             for p in 0..np {
-//                 let mut sets_r = self.clone();
-//                 let mut sets_l = Self::new_empty(self.dataset, self.scores);
-
-//                 let n_cuts = sets_r.sets[p].len();
-//                 for _ in 0..(n_cuts) {
-//                     let (cut_point, ids) = sets_r.sets[p].first_key_value().unwrap();
-//                     let cut_point = cut_point.clone();
-
-//                     // TODO: very expensive. try and remove
-//                     for index in ids {
-//                         sets_l.add_by_index(*index);
-//                     }
-
-//                     sets_r.remove_by_key(p, &cut_point);
-
-//                     let tree_l = sets_l.recursive_tree_search(depth - 1, false);
-//                     let tree_r = sets_r.recursive_tree_search(depth - 1, false);
-
-//                     let current_reward = tree_l.reward + tree_r.reward;
-
-//                     if current_reward > best_reward {
-//                         best_l_tree = tree_l;
-//                         best_r_tree = tree_r;
-//                         best_reward = current_reward;
-//                         best_split_axis = p;
-//                         best_split_point = cut_point;
-//                     }
-//                 }
-//             }
-
-//             return Node::new_branch(best_l_tree, best_r_tree, best_split_axis, best_split_point)
-//         }
-//     }
-// }
@@ -0,0 +1,216 @@
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// How often the thread that launched a search wakes up to check for interrupts, and how often it
+// hands a progress snapshot to the reporting callback.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+const REPORT_INTERVAL: Duration = Duration::from_secs(5);
+
+// SearchMonitor Struct. Shared by every searcher taking part in one tree search. Workers only bump
+// atomic counters, so progress can be published from any of the rayon threads, and the thread that
+// launched the search reads snapshots of it through `progress`. It also carries the flag telling
+// every worker to wind down early, which the searchers poll between cut points, and is raised
+// either when the user interrupts or when the optional wall-clock budget runs out.
+pub struct SearchMonitor {
+    start: Instant,
+    deadline: Option<Instant>,
+    stop: AtomicBool,
+    cancelled: AtomicBool,
+    timed_out: AtomicBool,
+    total_dims: usize,
+    total_top_cuts: usize,
+    dims_done: AtomicUsize,
+    top_cuts_done: AtomicUsize,
+    cuts_done: AtomicUsize,
+    pruned: AtomicUsize,
+}
+
+// Snapshot of a running search, as handed to the progress callback.
+#[derive(Debug, Clone)]
+pub struct Progress {
+    pub dims_done: usize,
+    pub total_dims: usize,
+    pub top_cuts_done: usize,
+    pub total_top_cuts: usize,
+    pub cuts_done: usize,
+    pub pruned: usize,
+    pub elapsed: Duration,
+}
+
+impl SearchMonitor {
+    pub fn new(total_dims: usize, total_top_cuts: usize, time_limit: Option<Duration>) -> Self {
+        let start = Instant::now();
+        SearchMonitor {
+            start: start,
+            deadline: time_limit.map(|limit| start + limit),
+            stop: AtomicBool::new(false),
+            cancelled: AtomicBool::new(false),
+            timed_out: AtomicBool::new(false),
+            total_dims: total_dims,
+            total_top_cuts: total_top_cuts,
+            dims_done: AtomicUsize::new(0),
+            top_cuts_done: AtomicUsize::new(0),
+            cuts_done: AtomicUsize::new(0),
+            pruned: AtomicUsize::new(0),
+        }
+    }
+
+    // Checked by the searchers between cut points. Once set, every search returns the best tree it
+    // has found so far as soon as possible.
+    pub fn should_stop(&self) -> bool {
+        self.stop.load(Ordering::Relaxed)
+    }
+
+    // Abandons the search. Its result should be thrown away.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+        self.stop.store(true, Ordering::Relaxed);
+    }
+
+    pub fn cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    // Stops the search because the time budget ran out. Unlike `cancel`, the result is still used:
+    // it is the best tree found so far, just not certified optimal.
+    fn time_out(&self) {
+        self.timed_out.store(true, Ordering::Relaxed);
+        self.stop.store(true, Ordering::Relaxed);
+    }
+
+    pub fn timed_out(&self) -> bool {
+        self.timed_out.load(Ordering::Relaxed)
+    }
+
+    fn out_of_time(&self) -> bool {
+        match self.deadline {
+            Some(deadline) => Instant::now() >= deadline,
+            None => false,
+        }
+    }
+
+    // A top-level dimension has been searched completely
+    pub fn dimension_finished(&self) {
+        self.dims_done.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // `n` cut points of a top-level dimension have been dealt with
+    pub fn top_cuts_finished(&self, n: usize) {
+        self.top_cuts_done.fetch_add(n, Ordering::Relaxed);
+    }
+
+    // `n` cut points have been evaluated, at any level of the tree
+    pub fn cuts_processed(&self, n: usize) {
+        self.cuts_done.fetch_add(n, Ordering::Relaxed);
+    }
+
+    // `n` subtree searches were skipped because their bound showed they could not win
+    pub fn subtrees_pruned(&self, n: usize) {
+        self.pruned.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn progress(&self) -> Progress {
+        Progress {
+            dims_done: self.dims_done.load(Ordering::Relaxed),
+            total_dims: self.total_dims,
+            top_cuts_done: self.top_cuts_done.load(Ordering::Relaxed),
+            total_top_cuts: self.total_top_cuts,
+            cuts_done: self.cuts_done.load(Ordering::Relaxed),
+            pruned: self.pruned.load(Ordering::Relaxed),
+            elapsed: self.start.elapsed(),
+        }
+    }
+}
+
+impl Progress {
+    // Share of the top-level cut points that have been searched
+    pub fn fraction(&self) -> f64 {
+        if self.total_top_cuts == 0 {
+            return 0.0;
+        }
+        (self.top_cuts_done as f64 / self.total_top_cuts as f64).min(1.0)
+    }
+
+    // Linear extrapolation of the elapsed time. None until some progress has been made.
+    pub fn remaining(&self) -> Option<Duration> {
+        let fraction = self.fraction();
+        if fraction <= 0.0 {
+            return None;
+        }
+        Some(self.elapsed.mul_f64((1.0 - fraction) / fraction))
+    }
+}
+
+impl fmt::Display for Progress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}/{} dimensions searched ({:.1}% of top-level cut points), {} cut points processed, {} subtrees pruned, {:.1}s elapsed",
+            self.dims_done,
+            self.total_dims,
+            100.0 * self.fraction(),
+            self.cuts_done,
+            self.pruned,
+            self.elapsed.as_secs_f64()
+        )?;
+        match self.remaining() {
+            Some(remaining) => write!(f, ", ~{:.1}s remaining", remaining.as_secs_f64()),
+            None => Ok(()),
+        }
+    }
+}
+
+// Runs `search` on a separate thread, so the calling thread stays free to poll `interrupted` and
+// to call `report` with a progress snapshot every few seconds. Only the calling thread ever runs
+// the two callbacks, which makes it safe for them to talk to R while the search itself fans out
+// over the rayon pool. If `interrupted` returns true the monitor is cancelled, and the (partial)
+// result is still returned once every worker has wound down; check `SearchMonitor::cancelled`.
+// Likewise the monitor is timed out once its deadline passes; see `SearchMonitor::timed_out`.
+pub fn run_monitored<T, S, I, R>(
+    monitor: &SearchMonitor,
+    search: S,
+    mut interrupted: I,
+    mut report: R,
+) -> T
+where
+    T: Send,
+    S: FnOnce() -> T + Send,
+    I: FnMut() -> bool,
+    R: FnMut(&Progress),
+{
+    thread::scope(|scope| {
+        let (done_tx, done_rx) = channel();
+        let handle = scope.spawn(move || {
+            let out = search();
+            let _ = done_tx.send(());
+            out
+        });
+
+        let mut last_report = Instant::now();
+        let mut status = done_rx.recv_timeout(POLL_INTERVAL);
+        while let Err(RecvTimeoutError::Timeout) = status {
+            if !monitor.cancelled() && interrupted() {
+                monitor.cancel();
+            }
+            if !monitor.should_stop() && monitor.out_of_time() {
+                monitor.time_out();
+            }
+            if !monitor.cancelled() && last_report.elapsed() >= REPORT_INTERVAL {
+                report(&monitor.progress());
+                last_report = Instant::now();
+            }
+            status = done_rx.recv_timeout(POLL_INTERVAL);
+        }
+
+        // Anything but a timeout ends the loop: the search signalled that it is done, or it panicked
+        // and dropped the sender without signalling, which shows as `Disconnected`
+        match (status, handle.join()) {
+            (Ok(()), Ok(out)) => out,
+            (Err(RecvTimeoutError::Disconnected), Err(panic)) => std::panic::resume_unwind(panic),
+            _ => unreachable!("the search thread signals exactly when it returns"),
+        }
+    })
+}
@@ -20,6 +20,8 @@ pub struct Node {
     pub right_child: Option<Box<Node>>,
     pub cut_axis: Option<usize>,
     pub cut_point: Option<OrderedFloat<f64>>,
+    pub cut_levels: Option<Vec<OrderedFloat<f64>>>,
+    pub send_missing_left: bool,
 }
 
 impl Node {
@@ -32,6 +34,8 @@ impl Node {
             right_child: None,
             cut_axis: None,
             cut_point: None,
+            cut_levels: None,
+            send_missing_left: false,
         }
     }
     pub fn new_branch(
@@ -48,8 +52,88 @@ impl Node {
             right_child: Some(Box::new(right_child)),
             cut_axis: Some(axis),
             cut_point: Some(cut_point),
+            cut_levels: None,
+            send_missing_left: false,
         }
     }
+
+    // Branch on a categorical covariate, sending the observations whose level is one of `levels`
+    // to the left and the rest to the right
+    pub fn new_categorical_branch(
+        left_child: Node,
+        right_child: Node,
+        axis: usize,
+        levels: Vec<OrderedFloat<f64>>,
+    ) -> Self {
+        Self {
+            node_type: NodeType::Branch,
+            action: None,
+            reward: left_child.reward + right_child.reward,
+            left_child: Some(Box::new(left_child)),
+            right_child: Some(Box::new(right_child)),
+            cut_axis: Some(axis),
+            cut_point: None,
+            cut_levels: Some(levels),
+            send_missing_left: false,
+        }
+    }
+
+    // Sets which side of the cut observations missing the cut covariate are sent to
+    pub fn with_missing_left(mut self, send_missing_left: bool) -> Self {
+        self.send_missing_left = send_missing_left;
+        self
+    }
+
+    // Whether an observation with covariates `x` goes to the left child of this branch
+    pub fn sends_left(&self, x: ArrayView1<OrderedFloat<f64>>) -> bool {
+        let value = x[self.cut_axis.unwrap()];
+        if value.is_nan() {
+            return self.send_missing_left;
+        }
+        match &self.cut_levels {
+            Some(levels) => levels.contains(&value),
+            None => value <= self.cut_point.unwrap(),
+        }
+    }
+
+    // Action the tree recommends for an observation with covariates `x`
+    pub fn action_for(&self, x: ArrayView1<OrderedFloat<f64>>) -> usize {
+        let mut node = self;
+        while node.node_type == NodeType::Branch {
+            node = if node.sends_left(x) {
+                node.left_child.as_ref().unwrap()
+            } else {
+                node.right_child.as_ref().unwrap()
+            };
+        }
+        node.action.unwrap()
+    }
+
+    pub fn n_leaves(&self) -> usize {
+        match self.node_type {
+            NodeType::Leaf => 1,
+            NodeType::Branch => {
+                self.left_child.as_ref().unwrap().n_leaves()
+                    + self.right_child.as_ref().unwrap().n_leaves()
+            }
+        }
+    }
+
+    // Adds back the `penalty` charged to every leaf during a penalised search, so that the rewards
+    // in the tree are the plain total rewards again
+    pub fn remove_leaf_penalty(&mut self, penalty: OrderedFloat<f64>) {
+        if self.node_type == NodeType::Leaf {
+            self.reward += penalty;
+            return;
+        }
+
+        let left_child = self.left_child.as_mut().unwrap();
+        let right_child = self.right_child.as_mut().unwrap();
+        left_child.remove_leaf_penalty(penalty);
+        right_child.remove_leaf_penalty(penalty);
+        self.reward = left_child.reward + right_child.reward;
+    }
+
     pub fn r_representation(&self) -> List {
         let mut queue: VecDeque<Node> = VecDeque::new();
         let mut output: Vec<List> = Vec::new();
@@ -72,13 +156,24 @@ impl Node {
                     queue.push_back(left_child);
                     queue.push_back(right_child);
 
-                    output.push(list!(
-                        is_leaf = false,
-                        split_variable = current.cut_axis.unwrap() + 1,
-                        split_value = f64::from(current.cut_point.unwrap()),
-                        left_child = next_avaliable,
-                        right_child = next_avaliable + 1,
-                    ));
+                    match current.cut_levels {
+                        Some(levels) => output.push(list!(
+                            is_leaf = false,
+                            split_variable = current.cut_axis.unwrap() + 1,
+                            split_levels = levels.iter().map(|x| f64::from(*x)).collect::<Vec<f64>>(),
+                            left_child = next_avaliable,
+                            right_child = next_avaliable + 1,
+                            send_missing_left = current.send_missing_left,
+                        )),
+                        None => output.push(list!(
+                            is_leaf = false,
+                            split_variable = current.cut_axis.unwrap() + 1,
+                            split_value = f64::from(current.cut_point.unwrap()),
+                            left_child = next_avaliable,
+                            right_child = next_avaliable + 1,
+                            send_missing_left = current.send_missing_left,
+                        )),
+                    }
                     next_avaliable += 2;
                 }
             }
@@ -87,65 +182,25 @@ impl Node {
         List::from_values(output)
     }
 
+    // Collapses every branch whose subtrees all recommend the same action into a single leaf. The
+    // children are pruned first, so a subtree of any depth that only ever recommends one action ends
+    // up as one leaf. Predictions and total reward are unchanged.
     pub fn prune(&mut self) {
-        if matches!(self.node_type, NodeType::Leaf) {
-            return
+        if self.node_type == NodeType::Leaf {
+            return;
         }
 
-        // Make Sure that no branch has two leaves that reccomend the same action
-        match (self.left_child.as_ref().unwrap().node_type, self.right_child.as_ref().unwrap().node_type) {
-            (NodeType::Branch, NodeType::Leaf) => {
-                self.left_child.as_mut().unwrap().prune();
-            }
-            (NodeType::Leaf, NodeType::Branch) => {
-                self.right_child.as_mut().unwrap().prune();
-            }
+        let left_child = self.left_child.as_mut().unwrap();
+        let right_child = self.right_child.as_mut().unwrap();
+        left_child.prune();
+        right_child.prune();
 
-            (NodeType::Branch, NodeType::Branch) => {
-                // Make sure that no cuts are made twice in a row. This is a mess, but it's not performance-critical
-                if self.left_child.as_ref().unwrap().cut_axis.unwrap() == self.cut_axis.unwrap() {
-                    if self.left_child.as_ref().unwrap().cut_point.unwrap() == self.cut_point.unwrap() {
-                        self.left_child = self.left_child.as_ref().unwrap().left_child.clone();
-                    }
-                } else if self.right_child.as_ref().unwrap().cut_axis.unwrap() == self.cut_axis.unwrap() {
-                    if self.right_child.as_ref().unwrap().cut_point.unwrap() == self.cut_point.unwrap() {
-                        self.right_child = self.right_child.as_ref().unwrap().right_child.clone();
-                    }
-                } else {
-                    self.left_child.as_mut().unwrap().prune();
-                    self.right_child.as_mut().unwrap().prune();
-                    match (self.left_child.as_ref().unwrap().node_type, self.right_child.as_ref().unwrap().node_type) {
-                        (NodeType::Leaf, NodeType::Leaf) => {
-                            // println!("D");
-                            if self.left_child.as_ref().unwrap().action == self.right_child.as_ref().unwrap().action {
-                                self.node_type = NodeType::Leaf;
-                                self.action = self.left_child.as_ref().unwrap().action;
-                                self.reward =  self.left_child.as_ref().unwrap().reward + self.right_child.as_ref().unwrap().reward;
-                                self.left_child =  None;
-                                self.right_child =  None;
-                                self.cut_axis =  None;
-                                self.cut_point =  None;
-                            }
-                        }
-                        (_,_) => ()
-                    }
-                }
-            }
-            (NodeType::Leaf, NodeType::Leaf) => {
-                // println!("D");
-                if self.left_child.as_ref().unwrap().action == self.right_child.as_ref().unwrap().action {
-                    self.node_type = NodeType::Leaf;
-                    self.action = self.left_child.as_ref().unwrap().action;
-                    self.reward =  self.left_child.as_ref().unwrap().reward + self.right_child.as_ref().unwrap().reward;
-                    self.left_child =  None;
-                    self.right_child =  None;
-                    self.cut_axis =  None;
-                    self.cut_point =  None;
-                }
-            }
+        if left_child.node_type == NodeType::Leaf
+            && right_child.node_type == NodeType::Leaf
+            && left_child.action == right_child.action
+        {
+            *self = Node::new_leaf(left_child.reward + right_child.reward, left_child.action.unwrap());
         }
-
-
     }
 }
 
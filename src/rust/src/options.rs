@@ -0,0 +1,115 @@
+use extendr_api::prelude::*;
+use std::time::Duration;
+
+// Settings of a search, passed from R as one named list rather than as positional arguments so
+// that the entry points don't depend on the order of a long argument list. Entries that are left
+// out keep their defaults, which search every row without limits; each entry point only reads the
+// settings that apply to it. See `rust_exhaustive_tree` for what they do.
+#[derive(Debug, Clone)]
+pub struct SearchOptions {
+    pub depth: usize,
+    pub min_node_size: f64,
+    pub weighted_size: bool,
+    pub verbose: bool,
+    pub bound_pruning: bool,
+    pub num_threads: i64,
+    pub time_limit: Option<Duration>,
+    pub prune: bool,
+    pub capacity: Vec<f64>,
+    pub capacity_counts: bool,
+    pub eligibility_fraction: f64,
+    pub penalty: f64,
+    pub pruning_path: bool,
+    pub top_k: usize,
+    pub rashomon_epsilon: f64,
+    pub rashomon_relative: bool,
+    pub rashomon_max_trees: usize,
+}
+
+impl Default for SearchOptions {
+    fn default() -> Self {
+        SearchOptions {
+            depth: 0,
+            min_node_size: 1.0,
+            weighted_size: false,
+            verbose: false,
+            bound_pruning: true,
+            num_threads: 0,
+            time_limit: None,
+            prune: false,
+            capacity: Vec::new(),
+            capacity_counts: false,
+            eligibility_fraction: 1.0,
+            penalty: 0.0,
+            pruning_path: false,
+            top_k: 0,
+            rashomon_epsilon: 0.0,
+            rashomon_relative: false,
+            rashomon_max_trees: 0,
+        }
+    }
+}
+
+impl SearchOptions {
+    // Reads the options from a named R list, failing on names it doesn't know and on values of the
+    // wrong type, so that a typo on the R side can't silently fall back to a default
+    pub fn from_list(robj: &Robj) -> Result<Self> {
+        let list = robj
+            .as_list()
+            .ok_or_else(|| Error::Other("Search options must be a named list".to_string()))?;
+
+        let mut options = SearchOptions::default();
+        for (name, value) in list.iter() {
+            match name {
+                "depth" => options.depth = number(name, &value)? as usize,
+                "min_node_size" => options.min_node_size = number(name, &value)?,
+                "weighted_size" => options.weighted_size = flag(name, &value)?,
+                "verbose" => options.verbose = flag(name, &value)?,
+                "bound_pruning" => options.bound_pruning = flag(name, &value)?,
+                "num_threads" => options.num_threads = number(name, &value)? as i64,
+                "time_limit" => {
+                    let seconds = number(name, &value)?;
+                    options.time_limit = if seconds.is_finite() {
+                        Some(Duration::from_secs_f64(seconds))
+                    } else {
+                        None
+                    };
+                }
+                "prune" => options.prune = flag(name, &value)?,
+                "capacity" => {
+                    options.capacity = value.as_real_vector().ok_or_else(|| {
+                        Error::Other(
+                            "Search option `capacity` must be a numeric vector".to_string(),
+                        )
+                    })?;
+                }
+                "capacity_counts" => options.capacity_counts = flag(name, &value)?,
+                "eligibility_fraction" => options.eligibility_fraction = number(name, &value)?,
+                "penalty" => options.penalty = number(name, &value)?,
+                "pruning_path" => options.pruning_path = flag(name, &value)?,
+                "top_k" => options.top_k = number(name, &value)? as usize,
+                "rashomon_epsilon" => options.rashomon_epsilon = number(name, &value)?,
+                "rashomon_relative" => options.rashomon_relative = flag(name, &value)?,
+                "rashomon_max_trees" => options.rashomon_max_trees = number(name, &value)? as usize,
+                _ => return Err(Error::Other(format!("Unknown search option `{}`", name))),
+            }
+        }
+
+        Ok(options)
+    }
+}
+
+// A single number, given by R as a double or an integer
+fn number(name: &str, value: &Robj) -> Result<f64> {
+    value
+        .as_real()
+        .or_else(|| value.as_integer().map(f64::from))
+        .ok_or_else(|| Error::Other(format!("Search option `{}` must be a number", name)))
+}
+
+// A single TRUE or FALSE
+fn flag(name: &str, value: &Robj) -> Result<bool> {
+    value
+        .as_bool()
+        .ok_or_else(|| Error::Other(format!("Search option `{}` must be TRUE or FALSE", name)))
+}
@@ -0,0 +1,74 @@
+use extendr_api::prelude::*;
+use rayon::prelude::*;
+
+// Columns of the matrix a fitted tree is passed in from R, one row per node in the order of
+// `Node::r_representation`. Leaves have a split variable of 0. Node ids are 1-based, as in R.
+const SPLIT_VARIABLE: usize = 0;
+const SPLIT_VALUE: usize = 1;
+const LEFT_CHILD: usize = 2;
+const RIGHT_CHILD: usize = 3;
+const SEND_MISSING_LEFT: usize = 4;
+const ACTION: usize = 5;
+const FIRST_LEVEL: usize = 6;
+const N_LEVELS: usize = 7;
+
+// FlatTree Struct. A fitted tree as laid out by `predict.sparse_policy_tree`: a matrix with a row
+// for each node, and the levels sent left by every categorical split one after the other in
+// `levels`, each split holding the (1-based) position of its first level and how many it has.
+pub struct FlatTree<'a> {
+    nodes: ArrayView2<'a, f64>,
+    levels: ArrayView1<'a, f64>,
+}
+
+impl<'a> FlatTree<'a> {
+    pub fn new(nodes: ArrayView2<'a, f64>, levels: ArrayView1<'a, f64>) -> Self {
+        FlatTree {
+            nodes: nodes,
+            levels: levels,
+        }
+    }
+
+    // Row of the leaf an observation with covariates `x` falls in, routed as in `Node::sends_left`
+    fn leaf(&self, x: ArrayView1<f64>) -> usize {
+        let mut node = 0;
+        loop {
+            let split = self.nodes.row(node);
+            if split[SPLIT_VARIABLE] == 0.0 {
+                return node;
+            }
+
+            let value = x[split[SPLIT_VARIABLE] as usize - 1];
+            let go_left = if value.is_nan() {
+                split[SEND_MISSING_LEFT] != 0.0
+            } else if split[N_LEVELS] > 0.0 {
+                let first = split[FIRST_LEVEL] as usize - 1;
+                self.levels
+                    .iter()
+                    .skip(first)
+                    .take(split[N_LEVELS] as usize)
+                    .any(|level| *level == value)
+            } else {
+                value <= split[SPLIT_VALUE]
+            };
+
+            let child = if go_left { LEFT_CHILD } else { RIGHT_CHILD };
+            node = split[child] as usize - 1;
+        }
+    }
+
+    // Action (0-based) recommended for an observation with covariates `x`
+    pub fn action(&self, x: ArrayView1<f64>) -> usize {
+        self.nodes[[self.leaf(x), ACTION]] as usize - 1
+    }
+
+    // Recommended action and (1-based) leaf id for every row of `x`, computed in parallel
+    pub fn predict(&self, x: ArrayView2<f64>) -> (Vec<f64>, Vec<f64>) {
+        (0..x.nrows())
+            .into_par_iter()
+            .map(|row| {
+                let leaf = self.leaf(x.row(row));
+                (self.nodes[[leaf, ACTION]], (leaf + 1) as f64)
+            })
+            .unzip()
+    }
+}
@@ -0,0 +1,312 @@
+use ordered_float::OrderedFloat;
+use rayon::prelude::*;
+use std::cmp::Reverse;
+
+use crate::categorical::left_set;
+use crate::node::{Node, NodeType};
+use crate::{may_improve, TreeSearcher, BOUND_TOLERANCE};
+
+// Ranked tree search. Finds the `k` best distinct trees instead of only the best one, to show
+// whether the best tree stands out or is one of many near-ties. Trees are compared in the form
+// `Node::prune` leaves them in, so trees that only differ by branches whose leaves all recommend
+// the same action count once, and each way of splitting the observations at a node is only tried
+// once (see `new_partition`). Every search returns the `k` best trees over its observations, and a
+// split combines pairs from its two sides' lists, since the `k` best trees with a given split can
+// only be made from the `k` best trees on either side. With a `floor`, trees with a lower
+// reward are left out of every list, and subtrees whose reward bound rules out reaching the floor
+// aren't searched.
+impl<'a> TreeSearcher<'a> {
+    // The (at most) `k` best distinct trees of the given depth, best first. The first one is as good
+    // as the tree the unconstrained search finds.
+    pub fn best_trees(&self, depth: usize, k: usize) -> Vec<Node> {
+        self.ranked_tree_search(depth, true, k, OrderedFloat(-f64::INFINITY))
+    }
+
+    // The Rashomon set: the distinct trees of the given depth with a reward of at least `floor`,
+    // best first, or the `max_trees` best of them if there are more
+    pub fn rashomon_set(
+        &self,
+        depth: usize,
+        max_trees: usize,
+        floor: OrderedFloat<f64>,
+    ) -> Vec<Node> {
+        self.ranked_tree_search(depth, true, max_trees, floor)
+    }
+
+    // Leaves giving every active unit one action, for each action the eligibility mask allows
+    fn leaf_ranking(&self, floor: OrderedFloat<f64>) -> Vec<Node> {
+        let nd: usize = self.ctx.scores.dim().1;
+
+        (0..nd)
+            .filter(|action| self.ctx.allowed(self.ineligible[*action], self.n_active))
+            .map(|action| self.ctx.leaf(self.max_treatment_utils[action], action))
+            .filter(|leaf| may_improve(leaf.reward, floor))
+            .collect()
+    }
+
+    // Counterpart of `recursive_tree_search`, returning the `k` best distinct trees of the given
+    // depth over the active observations. A leaf is the pruned form of any tree whose leaves all
+    // recommend its action, so the leaves always take part, after the splits.
+    fn ranked_tree_search(
+        &self,
+        depth: usize,
+        top: bool,
+        k: usize,
+        floor: OrderedFloat<f64>,
+    ) -> Vec<Node> {
+        if !may_improve(self.upper_bound, floor) {
+            self.ctx.monitor.subtrees_pruned(1);
+            return Vec::new();
+        }
+        if depth == 0 {
+            return top_distinct(self.leaf_ranking(floor), k);
+        }
+
+        let np: usize = self.ctx.sets.len();
+        let splits: Vec<Option<Vec<Node>>> = if top {
+            (0..np)
+                .into_par_iter()
+                .map(|dim| self.single_dimension_ranked_search(dim, depth, top, k, floor))
+                .collect()
+        } else {
+            (0..np)
+                .map(|dim| self.single_dimension_ranked_search(dim, depth, top, k, floor))
+                .collect()
+        };
+
+        let mut candidates: Vec<Node> = splits.into_iter().flatten().flatten().collect();
+        candidates.extend(self.leaf_ranking(floor));
+
+        top_distinct(candidates, k)
+    }
+
+    // The `k` best distinct trees splitting on `dim` at the top and reaching `floor`, or None if no
+    // cut point along it is allowed by `min_node_size`
+    fn single_dimension_ranked_search(
+        &self,
+        dim: usize,
+        depth: usize,
+        top: bool,
+        k: usize,
+        floor: OrderedFloat<f64>,
+    ) -> Option<Vec<Node>> {
+        let n_cuts = self.ctx.steps[dim].len();
+        let present = self.present_levels(dim);
+
+        let mut candidates: Vec<Node> = Vec::new();
+        let mut found_split = false;
+
+        for &missing_left in self.ctx.missing_directions(dim) {
+            let has_missing = self
+                .ctx
+                .missing(dim)
+                .iter()
+                .any(|index| self.active[*index]);
+            if missing_left && !has_missing {
+                if top {
+                    self.ctx.monitor.top_cuts_finished(n_cuts);
+                }
+                continue;
+            }
+
+            let mut sets_r = self.clone();
+            let mut sets_l = Self::new_empty(self.ctx);
+
+            if missing_left {
+                for index in self.ctx.missing(dim) {
+                    if self.active[*index] {
+                        sets_l.add(*index);
+                        sets_r.remove(*index);
+                    }
+                }
+            }
+            let n_missing_left = sets_l.n_active;
+
+            for (i, step) in self.ctx.steps[dim].iter().enumerate() {
+                if self.ctx.monitor.should_stop() {
+                    break;
+                }
+
+                if top {
+                    self.ctx.monitor.top_cuts_finished(1);
+                }
+                self.ctx.monitor.cuts_processed(1);
+
+                let n_left = sets_l.n_active;
+                self.move_bundle(dim, *step, &mut sets_l, &mut sets_r);
+
+                if sets_r.size < self.ctx.min_node_size {
+                    if !self.ctx.monotone(dim) {
+                        continue;
+                    }
+                    if top {
+                        self.ctx.monitor.top_cuts_finished(n_cuts - i - 1);
+                    }
+                    break;
+                }
+                if sets_l.size < self.ctx.min_node_size {
+                    continue;
+                }
+                if !self
+                    .ctx
+                    .on_split_step(dim, sets_l.n_active - n_missing_left)
+                {
+                    continue;
+                }
+                found_split = true;
+
+                if !self.new_partition(dim, i, n_left != sets_l.n_active, present) {
+                    continue;
+                }
+
+                // Each side only has to reach the floor less the most the other side could add
+                let ranking_l =
+                    sets_l.ranked_tree_search(depth - 1, false, k, floor - sets_r.upper_bound);
+                let best_l = match ranking_l.first() {
+                    Some(tree) => tree.reward,
+                    None => continue,
+                };
+                let ranking_r = sets_r.ranked_tree_search(depth - 1, false, k, floor - best_l);
+                let best_r = match ranking_r.first() {
+                    Some(tree) => tree.reward,
+                    None => continue,
+                };
+
+                // Both rankings are sorted best first, so a pair falling short of the floor ends its
+                // row, and a left tree falling short with the best right tree ends the search. The
+                // pair of the a-th and b-th trees is beaten by the (a + 1)(b + 1) - 1 distinct pairs
+                // before it on both sides, so only pairs with (a + 1)(b + 1) <= k can be among the
+                // `k` best, which keeps the pairs tried to about k log k rather than k squared.
+                for (a, tree_l) in ranking_l.iter().enumerate() {
+                    if !may_improve(tree_l.reward + best_r, floor) {
+                        break;
+                    }
+                    for tree_r in ranking_r.iter().take(k / (a + 1)) {
+                        if !may_improve(tree_l.reward + tree_r.reward, floor) {
+                            break;
+                        }
+                        candidates.push(self.combine(tree_l, tree_r, dim, i, missing_left));
+                    }
+                }
+
+                // Thin the candidates out every so often, so that they don't pile up over the cut points
+                if candidates.len() > 2 * k + 64 {
+                    candidates = top_distinct(candidates, k);
+                }
+            }
+        }
+
+        if top {
+            self.ctx.monitor.dimension_finished();
+        }
+
+        if !found_split {
+            return None;
+        }
+
+        Some(top_distinct(candidates, k))
+    }
+
+    // Bit mask of the levels of categorical covariate `dim` that some active observation has
+    fn present_levels(&self, dim: usize) -> usize {
+        if self.ctx.monotone(dim) {
+            return 0;
+        }
+
+        self.ctx.sets[dim]
+            .iter()
+            .enumerate()
+            .filter(|(_, bundle)| bundle.indexes.iter().any(|index| self.active[*index]))
+            .fold(0, |mask, (level, _)| mask | (1 << level))
+    }
+
+    // Whether the cut after step `step` along `dim` splits the active observations in a way that no
+    // other cut along it does, given whether the step `moved` any of them and the levels `present`
+    // among them. On a numeric axis that means it moved some. On a categorical axis, the same
+    // split is reached with the levels no active observation has on either side, and again with
+    // the two sides swapped, so only the state with all of those levels and the last present one
+    // on the right counts.
+    fn new_partition(&self, dim: usize, step: usize, moved: bool, present: usize) -> bool {
+        if self.ctx.monotone(dim) {
+            return moved;
+        }
+
+        let last_present = usize::BITS - 1 - present.leading_zeros();
+        left_set(step) & (!present | (1 << last_present)) == 0
+    }
+
+    // Tree splitting along `dim` as the sweep does after step `step`, in pruned form: two leaves
+    // recommending the same action make up a single leaf
+    fn combine(
+        &self,
+        tree_l: &Node,
+        tree_r: &Node,
+        dim: usize,
+        step: usize,
+        missing_left: bool,
+    ) -> Node {
+        if tree_l.node_type == NodeType::Leaf
+            && tree_r.node_type == NodeType::Leaf
+            && tree_l.action == tree_r.action
+        {
+            // Both leaves were charged the penalty, a single one is only charged it once
+            let reward = tree_l.reward + tree_r.reward + self.ctx.leaf_penalty;
+            return self.ctx.leaf(reward, tree_l.action.unwrap());
+        }
+
+        self.ctx
+            .branch(tree_l.clone(), tree_r.clone(), dim, step)
+            .with_missing_left(missing_left)
+    }
+}
+
+// The (at most) `k` best distinct trees among the candidates, best first. The sort is stable and
+// the first of several equal trees is kept, so ties keep the order the candidates were found in.
+// Equal trees have equal rewards up to rounding error, so each candidate is only compared with the
+// kept trees whose reward is that close to its own.
+fn top_distinct(mut candidates: Vec<Node>, k: usize) -> Vec<Node> {
+    candidates.sort_by_key(|tree| Reverse(tree.reward));
+
+    let mut kept: Vec<Node> = Vec::new();
+    for candidate in candidates {
+        if kept.len() == k {
+            break;
+        }
+        let duplicate = kept
+            .iter()
+            .rev()
+            .take_while(|tree| {
+                (tree.reward - candidate.reward).0 <= BOUND_TOLERANCE * (1.0 + tree.reward.0.abs())
+            })
+            .any(|tree| same_tree(tree, &candidate));
+        if !duplicate {
+            kept.push(candidate);
+        }
+    }
+    kept
+}
+
+// Whether two trees make the same splits and recommend the same actions
+fn same_tree(a: &Node, b: &Node) -> bool {
+    if a.node_type != b.node_type {
+        return false;
+    }
+
+    match a.node_type {
+        NodeType::Leaf => a.action == b.action,
+        NodeType::Branch => {
+            a.cut_axis == b.cut_axis
+                && a.cut_point == b.cut_point
+                && a.cut_levels == b.cut_levels
+                && a.send_missing_left == b.send_missing_left
+                && same_tree(
+                    a.left_child.as_ref().unwrap(),
+                    b.left_child.as_ref().unwrap(),
+                )
+                && same_tree(
+                    a.right_child.as_ref().unwrap(),
+                    b.right_child.as_ref().unwrap(),
+                )
+        }
+    }
+}
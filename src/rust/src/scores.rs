@@ -0,0 +1,47 @@
+use extendr_api::prelude::*;
+use rayon::prelude::*;
+
+// Doubly robust (AIPW) scores, the rewards the search is usually run on. For unit i and action d
+//
+//     Gamma[i, d] = mu[i, d] + 1{W[i] = d} (Y[i] - mu[i, d]) / max(e[i, d], clip)
+//
+// with outcome-model predictions `mu` and propensities `e`. Propensities below `clip` are raised
+// to it, which keeps a few units with tiny propensities from dominating the scores. Returns the
+// scores one action after the other (column-major, as R lays out matrices), and for every action
+// the number of units whose propensity for it was clipped, a rough check of overlap.
+pub fn aipw_scores(
+    y: ArrayView1<f64>,
+    w: ArrayView1<f64>,
+    propensities: ArrayView2<f64>,
+    predictions: ArrayView2<f64>,
+    clip: f64,
+) -> (Vec<f64>, Vec<usize>) {
+    let (n, nd) = predictions.dim();
+
+    let mut scores: Vec<f64> = Vec::with_capacity(n * nd);
+    let mut clipped: Vec<usize> = Vec::with_capacity(nd);
+    for action in 0..nd {
+        let column: Vec<(f64, bool)> = (0..n)
+            .into_par_iter()
+            .map(|row| {
+                let mu = predictions[[row, action]];
+                let propensity = propensities[[row, action]];
+                // Actions come from R, so they are 1-based
+                if w[row] as usize != action + 1 {
+                    return (mu, propensity < clip);
+                }
+                (mu + (y[row] - mu) / propensity.max(clip), propensity < clip)
+            })
+            .collect();
+
+        clipped.push(
+            column
+                .iter()
+                .filter(|(_, was_clipped)| *was_clipped)
+                .count(),
+        );
+        scores.extend(column.into_iter().map(|(score, _)| score));
+    }
+
+    (scores, clipped)
+}
@@ -0,0 +1,63 @@
+use extendr_api::prelude::*;
+use ordered_float::OrderedFloat;
+use std::collections::VecDeque;
+
+use crate::node::{Node, NodeType};
+use crate::TreeSearcher;
+
+impl<'a> TreeSearcher<'a> {
+    // Statistics of the active observations reaching each node of `tree`, whose covariates are the
+    // rows of `x`, in the order of `Node::r_representation`: their number, the summed and mean
+    // reward from each action, and for a leaf the summed reward of the action it recommends.
+    // Rewards are the ones searched on, so they are weighted by the sample weights and net of the
+    // costs, and means are weighted means, or NA for a node whose observations all have weight 0.
+    pub fn node_statistics(&self, tree: &Node, x: ArrayView2<OrderedFloat<f64>>) -> List {
+        let mut queue: VecDeque<(&Node, Self)> = VecDeque::new();
+        let mut output: Vec<List> = Vec::new();
+
+        queue.push_back((tree, self.clone()));
+        while let Some((node, searcher)) = queue.pop_front() {
+            let sums: Vec<f64> = searcher
+                .max_treatment_utils
+                .iter()
+                .map(|reward| f64::from(*reward))
+                .collect();
+            let means: Vec<f64> = sums
+                .iter()
+                .map(|sum| {
+                    if searcher.population > 0.0 {
+                        sum / searcher.population
+                    } else {
+                        f64::na()
+                    }
+                })
+                .collect();
+
+            match node.node_type {
+                NodeType::Leaf => {
+                    let reward = sums[node.action.unwrap()];
+                    output.push(list!(
+                        samples = searcher.n_active,
+                        reward_sums = sums,
+                        reward_means = means,
+                        reward = reward
+                    ));
+                }
+
+                NodeType::Branch => {
+                    output.push(list!(
+                        samples = searcher.n_active,
+                        reward_sums = sums,
+                        reward_means = means
+                    ));
+
+                    let (sets_l, sets_r) = searcher.route(node, x);
+                    queue.push_back((node.left_child.as_ref().unwrap(), sets_l));
+                    queue.push_back((node.right_child.as_ref().unwrap(), sets_r));
+                }
+            }
+        }
+
+        List::from_values(output)
+    }
+}